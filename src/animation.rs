@@ -0,0 +1,172 @@
+use std::ops::Range;
+use std::f32;
+use vec3::Vec3;
+use color3f::Color3f;
+use quaternion::Quaternion;
+use raytracer::{RenderConfig,Scene,Camera};
+use {render_scene,render_scene_headless,max_value_of_photon_buffer,dump_hdr_postprocessed_image};
+
+/* Animation time is in seconds. Rays carry the time they were sampled at
+ * (see Camera shutter), so colour and path programs can evaluate tracks at
+ * isect.ray.time to animate material parameters, and Primitive::Moving
+ * animates geometry. */
+
+pub trait Interpolate: Copy {
+    fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, other: &f32, t: f32) -> f32 { *self + (*other - *self)*t }
+}
+impl Interpolate for Vec3 {
+    fn interpolate(&self, other: &Vec3, t: f32) -> Vec3 { self.lerp(other, t) }
+}
+impl Interpolate for Color3f {
+    fn interpolate(&self, other: &Color3f, t: f32) -> Color3f { self.lerp(other, t) }
+}
+impl Interpolate for Quaternion {
+    fn interpolate(&self, other: &Quaternion, t: f32) -> Quaternion { self.slerp(other, t) }
+}
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    Smooth  // ease in and out of each key
+}
+
+/* Keyframes as (time, value) pairs in strictly increasing time order. Values
+ * are held constant before the first and after the last key. */
+#[derive(Clone)]
+pub struct Track<T> {
+    pub keys: Vec<(f32, T)>,
+    pub interpolation: Interpolation
+}
+
+impl<T: Interpolate> Track<T> {
+    pub fn new(keys: Vec<(f32, T)>) -> Track<T> {
+        assert!(!keys.is_empty());
+        // keys at the same time would leave a span of no length to interpolate over
+        assert!(keys.windows(2).all(|k| k[0].0 < k[1].0), "track keys must be in strictly increasing time order");
+        Track { keys: keys, interpolation: Interpolation::Linear }
+    }
+    pub fn constant(value: T) -> Track<T> {
+        Track::new(vec![(0., value)])
+    }
+    pub fn at(&self, time: f32) -> T {
+        if time <= self.keys[0].0 {
+            return self.keys[0].1;
+        }
+        for k in self.keys.windows(2) {
+            let (t0, ref v0) = k[0];
+            let (t1, ref v1) = k[1];
+            if time < t1 {
+                let f = (time - t0) / (t1 - t0);
+                return match self.interpolation {
+                    Interpolation::Step => *v0,
+                    Interpolation::Linear => v0.interpolate(v1, f),
+                    Interpolation::Smooth => v0.interpolate(v1, f*f*(3. - 2.*f))
+                };
+            }
+        }
+        self.keys[self.keys.len()-1].1
+    }
+}
+
+pub struct CameraTrack {
+    pub position: Track<Vec3>,
+    pub orientation: Track<Quaternion>
+}
+
+impl CameraTrack {
+    pub fn camera_at(&self, time: f32, shutter_duration: f32) -> Camera {
+        Camera {
+            position: self.position.at(time),
+            orientation: self.orientation.at(time),
+            shutter_open: time,
+            shutter_close: time + shutter_duration,
+            ..Camera::default()
+        }
+    }
+}
+
+pub struct Animation {
+    pub frames_per_second: f32,
+    // fraction of the frame interval the shutter is open (0.5 = 180 degree shutter). 0 disables motion blur
+    pub shutter: f32,
+    pub camera: CameraTrack
+}
+
+impl Animation {
+    pub fn frame_time(&self, frame: i32) -> f32 {
+        frame as f32 / self.frames_per_second
+    }
+    pub fn camera_for_frame(&self, frame: i32) -> Camera {
+        self.camera.camera_at(self.frame_time(frame), self.shutter / self.frames_per_second)
+    }
+}
+
+/* Render each frame in the range with 'iterations' passes, writing {file_prefix}_{frame:04}.ppm.
+ * With 'preview' each frame accumulates in the SDL window, otherwise rendering is headless.
+ * Every frame is tone mapped against the brightest pixel of the first, so the
+ * exposure doesn't flicker from frame to frame. */
+pub fn render_sequence(file_prefix: &str, frames: Range<i32>, iterations: i32, preview: bool,
+                       config: &RenderConfig, animation: &Animation, scene: &Scene) {
+    let mut max_value: Option<f32> = None;
+    for frame in frames {
+        let camera = animation.camera_for_frame(frame);
+        println!("Rendering frame {} (t={}s)", frame, animation.frame_time(frame));
+        let photon_buffer = if preview {
+            render_scene(iterations, config, &camera, scene)
+        } else {
+            render_scene_headless(iterations, config, &camera, scene)
+        };
+        let max_value = sequence_max_value(&mut max_value, &photon_buffer);
+        dump_hdr_postprocessed_image(&format!("{}_{:04}", file_prefix, frame), config.image_size,
+                                     config.preview_hdr_gamma, max_value, &photon_buffer);
+    }
+}
+
+// Exposure for the sequence, fixed by the first frame with any light in it. Until then
+// (e.g. a fade in from black) frames tone map against a tiny value rather than zero.
+fn sequence_max_value(max_value: &mut Option<f32>, photon_buffer: &Vec<Color3f>) -> f32 {
+    if max_value.is_none() {
+        let frame_max = max_value_of_photon_buffer(photon_buffer);
+        if frame_max > 0. {
+            *max_value = Some(frame_max);
+        }
+    }
+    max_value.unwrap_or(f32::EPSILON)
+}
+
+#[test]
+fn test_track() {
+    let mut track = Track::new(vec![(1., 0.), (2., 10.), (4., 20.)]);
+    assert_eq!(track.at(0.), 0.);
+    assert_eq!(track.at(1.5), 5.);
+    assert_eq!(track.at(3.), 15.);
+    assert_eq!(track.at(5.), 20.);
+    track.interpolation = Interpolation::Step;
+    assert_eq!(track.at(3.), 10.);
+    track.interpolation = Interpolation::Smooth;
+    assert_eq!(track.at(3.), 15.);
+    assert!(track.at(2.5) < 12.5);
+}
+
+#[test]
+#[should_panic]
+fn test_track_keys_increase() {
+    Track::new(vec![(0., 0.), (1., 10.), (1., 20.)]);
+}
+
+#[test]
+fn test_sequence_exposure_after_black_frame() {
+    let mut max_value = None;
+    let black = vec![Color3f::default(); 4];
+    let lit = vec![Color3f {r: 0.5, g: 2., b: 1.}; 4];
+    let brighter = vec![Color3f {r: 4., g: 4., b: 4.}; 4];
+    let exposure = sequence_max_value(&mut max_value, &black);
+    assert!(exposure > 0. && exposure.is_finite());
+    assert_eq!(sequence_max_value(&mut max_value, &lit), 2.);
+    assert_eq!(sequence_max_value(&mut max_value, &brighter), 2.);
+}
@@ -0,0 +1,101 @@
+use std::sync::Arc;
+use vec3::Vec3;
+use color3f::Color3f;
+use raytracer::{SceneObj,Primitive,Material};
+use volume::{Medium,Density,Phase};
+
+/* A planet and the sky above it, in the single scattering model of Nishita et
+ * al. 1993, "Display of the Earth Taking into Account Atmospheric Scattering",
+ * but path traced: air molecules Rayleigh scatter, aerosols Mie scatter and
+ * absorb, and both thin out exponentially with height above the ground */
+#[derive(Clone, Debug)]
+pub struct Atmosphere {
+    pub center: Vec3,
+    pub planet_radius: f32,
+    // thickness of the shell of sky, above which space is vacuum
+    pub height: f32,
+    pub rayleigh_scale_height: f32,
+    pub mie_scale_height: f32,
+    // extinction per unit distance at the ground. Rayleigh's is at 550nm, and
+    // goes as 1 / lambda^4
+    pub rayleigh_extinction: f32,
+    pub mie_extinction: f32,
+    // fraction of Mie extinction that is scattering rather than absorption
+    pub mie_albedo: f32,
+    // Mie asymmetry: haze scatters strongly forwards
+    pub mie_g: f32
+}
+
+// metres
+const EARTH_RADIUS: f32 = 6360e3;
+
+impl Atmosphere {
+    /* The Earth's atmosphere scaled to a planet of 'planet_radius', with sea
+     * level coefficients from Bruneton & Neyret 2008, "Precomputed Atmospheric
+     * Scattering" */
+    pub fn earth(center: Vec3, planet_radius: f32) -> Atmosphere {
+        let metre = planet_radius / EARTH_RADIUS;
+        Atmosphere {
+            center: center,
+            planet_radius: planet_radius,
+            height: 60e3 * metre,
+            rayleigh_scale_height: 8e3 * metre,
+            mie_scale_height: 1.2e3 * metre,
+            rayleigh_extinction: 13.5e-6 / metre,
+            mie_extinction: 21e-6 / 0.9 / metre,
+            mie_albedo: 0.9,
+            mie_g: 0.76
+        }
+    }
+
+    pub fn rayleigh_medium(&self) -> Medium {
+        Medium {
+            density: Density::Exponential { center: self.center, radius: self.planet_radius, scale_height: self.rayleigh_scale_height },
+            extinction: self.rayleigh_extinction,
+            albedo: Color3f {r: 1., g: 1., b: 1.},
+            phase: Phase::Rayleigh,
+            spectral_exponent: 4.
+        }
+    }
+
+    pub fn mie_medium(&self) -> Medium {
+        Medium {
+            density: Density::Exponential { center: self.center, radius: self.planet_radius, scale_height: self.mie_scale_height },
+            extinction: self.mie_extinction,
+            albedo: Color3f {r: self.mie_albedo, g: self.mie_albedo, b: self.mie_albedo},
+            phase: Phase::Mie(self.mie_g),
+            spectral_exponent: 0.
+        }
+    }
+
+    /* Scene objects for the planet: its ground, a sphere of material 'ground',
+     * and a shell of each medium filling the sky */
+    pub fn scene_objs(&self, ground: Material) -> Vec<SceneObj> {
+        let sky = Primitive::Sphere(self.center, self.planet_radius + self.height);
+        vec![
+            SceneObj { prim: Primitive::Sphere(self.center, self.planet_radius), mat: ground, medium: None },
+            SceneObj { prim: sky.clone(), mat: Material::default(), medium: Some(Arc::new(self.rayleigh_medium())) },
+            SceneObj { prim: sky, mat: Material::default(), medium: Some(Arc::new(self.mie_medium())) }
+        ]
+    }
+}
+
+#[test]
+fn test_earth_atmosphere() {
+    use raytracer::Ray;
+    use sampler::{Sampler,SamplerKind};
+    let earth = Atmosphere::earth(Vec3 {x: 0., y: 0., z: 0.}, 1.);
+    assert!((earth.height - 60. / 6360.).abs() < 1e-6);
+    assert_eq!(earth.scene_objs(Material::default()).len(), 3);
+    // looking straight up from the ground, green light sees a Rayleigh optical depth of about 0.1
+    let up = Ray {origin: Vec3 {x: 0., y: 1., z: 0.}, dir: Vec3 {x: 0., y: 1., z: 0.}, wavelength: 550., ..Ray::default()};
+    let mut rng = Sampler::new(SamplerKind::Random, ::rand::thread_rng());
+    let rayleigh = earth.rayleigh_medium();
+    let n = 10000;
+    let t: f32 = (0..n).map(|_| rayleigh.transmittance(&up, earth.height, &mut rng)).sum::<f32>() / n as f32;
+    assert!((t - (-0.108f32).exp()).abs() < 0.01, "{}", t);
+    // and blue light about 2.4 times that
+    let blue = Ray {wavelength: 440., ..up};
+    let t: f32 = (0..n).map(|_| rayleigh.transmittance(&blue, earth.height, &mut rng)).sum::<f32>() / n as f32;
+    assert!((t - (-0.108f32 * (550f32 / 440.).powi(4)).exp()).abs() < 0.02, "{}", t);
+}
@@ -1,4 +1,4 @@
-use std::ops::{Add, Mul, AddAssign};
+use std::ops::{Add, Sub, Mul, Div, AddAssign, MulAssign};
 
 
 #[derive(Debug, Copy, Clone, Default)]
@@ -20,6 +20,27 @@ impl Color3f {
             if self.g > self.b {self.g} else {self.b}
         }
     }
+    /* Relative luminance of linear Rec.709 primaries */
+    pub fn luminance(&self) -> f32 {
+        0.2126*self.r + 0.7152*self.g + 0.0722*self.b
+    }
+    pub fn lerp(&self, other: &Color3f, t: f32) -> Color3f {
+        *self + (*other - *self).smul(t)
+    }
+    pub fn clamp(&self, min: f32, max: f32) -> Color3f {
+        Color3f {r: self.r.max(min).min(max), g: self.g.max(min).min(max), b: self.b.max(min).min(max)}
+    }
+    fn map(&self, f: fn(f32) -> f32) -> Color3f {
+        Color3f {r: f(self.r), g: f(self.g), b: f(self.b)}
+    }
+    /* Encode linear [0..1] values with the sRGB transfer curve */
+    pub fn to_srgb(&self) -> Color3f {
+        self.map(|c| if c <= 0.0031308 { 12.92*c } else { 1.055*c.powf(1./2.4) - 0.055 })
+    }
+    /* Decode sRGB encoded [0..1] values to linear */
+    pub fn from_srgb(&self) -> Color3f {
+        self.map(|c| if c <= 0.04045 { c/12.92 } else { ((c + 0.055)/1.055).powf(2.4) })
+    }
 }
 
 impl Add for Color3f {
@@ -38,6 +59,14 @@ impl AddAssign for Color3f {
     }
 }
 
+impl Sub for Color3f {
+    type Output = Color3f;
+
+    fn sub(self, other: Color3f) -> Color3f {
+        Color3f { r: self.r-other.r, g: self.g-other.g, b: self.b-other.b }
+    }
+}
+
 impl Mul for Color3f {
     type Output = Color3f;
 
@@ -46,8 +75,45 @@ impl Mul for Color3f {
     }
 }
 
+impl MulAssign for Color3f {
+    fn mul_assign(&mut self, rhs: Color3f) {
+        *self = *self * rhs;
+    }
+}
+
+impl Div<f32> for Color3f {
+    type Output = Color3f;
+
+    fn div(self, s: f32) -> Color3f {
+        self.smul(1.0 / s)
+    }
+}
+
 impl PartialEq for Color3f {
     fn eq(&self, other: &Color3f) -> bool {
         return self.r==other.r && self.g==other.g && self.b==other.b
     }
 }
+
+#[test]
+fn test_color3f_ops() {
+    let mut c = Color3f {r: 1.0, g: 0.5, b: 0.25};
+    let d = Color3f {r: 0.5, g: 0.5, b: 2.0};
+    assert_eq!(c - d, Color3f {r: 0.5, g: 0.0, b: -1.75});
+    assert_eq!(c / 2.0, Color3f {r: 0.5, g: 0.25, b: 0.125});
+    assert_eq!(c.lerp(&d, 0.5), Color3f {r: 0.75, g: 0.5, b: 1.125});
+    assert_eq!(d.clamp(0.0, 1.0), Color3f {r: 0.5, g: 0.5, b: 1.0});
+    c *= d;
+    assert_eq!(c, Color3f {r: 0.5, g: 0.25, b: 0.5});
+    assert!((Color3f {r: 1.0, g: 1.0, b: 1.0}.luminance() - 1.0).abs() < 1e-6);
+}
+
+#[test]
+fn test_color3f_srgb() {
+    let c = Color3f {r: 0.0, g: 0.002, b: 0.5};
+    let s = c.to_srgb();
+    assert!((s.b - 0.7354).abs() < 1e-3);
+    let back = s.from_srgb();
+    assert!((back - c).max_channel() < 1e-5 && (c - back).max_channel() < 1e-5);
+    assert!((Color3f {r: 1.0, g: 1.0, b: 1.0}.to_srgb().r - 1.0).abs() < 1e-6);
+}
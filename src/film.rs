@@ -0,0 +1,265 @@
+use std::f32;
+use std::f32::consts::PI;
+use color3f::Color3f;
+
+/* Accumulated radiance for one pixel: the filter-weighted sum of samples
+ * splatted onto it, and the sum of those weights. Also the number of path
+ * samples traced from this pixel, and the running mean and sum of squared
+ * deviations (Welford) of the luminance of each of its eye rays' estimates */
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FilmPixel {
+    pub color: Color3f,
+    pub weight: f32,
+    pub samples: u32,
+    pub estimates: u32,
+    pub mean: f32,
+    pub m2: f32
+}
+
+impl FilmPixel {
+    pub fn add_estimate(&mut self, luminance: f32, samples: u32) {
+        self.samples += samples;
+        self.estimates += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.estimates as f32;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    pub fn variance(&self) -> f32 {
+        if self.estimates < 2 { 0. } else { self.m2 / (self.estimates - 1) as f32 }
+    }
+
+    /* Standard error of the pixel mean relative to the mean */
+    pub fn relative_error(&self) -> f32 {
+        if self.estimates < 2 {
+            return f32::INFINITY;
+        }
+        let std_error = (self.variance() / self.estimates as f32).sqrt();
+        if std_error == 0. { 0. } else { std_error / self.mean.max(1e-4) }
+    }
+
+    fn merge(&mut self, other: &FilmPixel) {
+        self.color += other.color;
+        self.weight += other.weight;
+        self.samples += other.samples;
+        if other.estimates > 0 {
+            // Chan et al. parallel variance
+            let n = (self.estimates + other.estimates) as f32;
+            let delta = other.mean - self.mean;
+            self.mean += delta * other.estimates as f32 / n;
+            self.m2 += other.m2 + delta*delta * self.estimates as f32 * other.estimates as f32 / n;
+            self.estimates += other.estimates;
+        }
+    }
+
+    pub fn resolve(&self) -> Color3f {
+        if self.weight > 0. {
+            // negative filter lobes can ring below zero
+            (self.color / self.weight).clamp(0., f32::INFINITY)
+        } else {
+            Color3f::black()
+        }
+    }
+}
+
+/* Pixel reconstruction filters, applied separably in x and y. 'radius' is in pixels */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Filter {
+    Box(f32),
+    Tent(f32),
+    Gaussian { radius: f32, alpha: f32 },
+    // B and C as in Mitchell & Netravali 1988. B=C=1/3 is the usual choice
+    Mitchell { radius: f32, b: f32, c: f32 },
+    Lanczos { radius: f32, tau: f32 }
+}
+
+impl Default for Filter {
+    fn default() -> Filter { Filter::Box(0.5) }
+}
+
+impl Filter {
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box(r) | Filter::Tent(r) => r,
+            Filter::Gaussian { radius, .. } | Filter::Mitchell { radius, .. } | Filter::Lanczos { radius, .. } => radius
+        }
+    }
+
+    /* Weight of a sample offset (dx, dy) pixels from a pixel centre */
+    pub fn eval(&self, dx: f32, dy: f32) -> f32 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+
+    fn eval_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        if x > self.radius() {
+            return 0.;
+        }
+        match *self {
+            Filter::Box(_) => 1.,
+            Filter::Tent(r) => r - x,
+            Filter::Gaussian { radius, alpha } => ((-alpha*x*x).exp() - (-alpha*radius*radius).exp()).max(0.),
+            Filter::Mitchell { radius, b, c } => {
+                let x = 2. * x / radius;
+                if x > 1. {
+                    ((-b - 6.*c) * x*x*x + (6.*b + 30.*c) * x*x + (-12.*b - 48.*c) * x + (8.*b + 24.*c)) / 6.
+                } else {
+                    ((12. - 9.*b - 6.*c) * x*x*x + (-18. + 12.*b + 6.*c) * x*x + (6. - 2.*b)) / 6.
+                }
+            }
+            Filter::Lanczos { tau, .. } => sinc(x) * sinc(x / tau)
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1e-5 { 1. } else { (PI*x).sin() / (PI*x) }
+}
+
+/* The rows of the film one render thread splats into: its own rows plus a
+ * border of the filter radius, merged back into the whole film afterwards */
+pub struct FilmTile {
+    width: u32,
+    y0: i32,
+    y1: i32,
+    pixels: Vec<FilmPixel>
+}
+
+impl FilmTile {
+    pub fn new(width: u32, height: u32, y_bounds: (i32, i32), filter: &Filter) -> FilmTile {
+        let border = (filter.radius() - 0.5).max(0.).ceil() as i32;
+        let y0 = (y_bounds.0 - border).max(0);
+        let y1 = (y_bounds.1 + border).min(height as i32);
+        FilmTile {
+            width: width,
+            y0: y0,
+            y1: y1,
+            pixels: vec![FilmPixel::default(); (width as i32 * (y1 - y0)) as usize]
+        }
+    }
+
+    /* Add 'color', the sum of 'count' samples taken at film position (x, y), to nearby pixels */
+    pub fn splat(&mut self, filter: &Filter, pos: (f32, f32), color: Color3f, count: u32) {
+        let r = filter.radius();
+        let px0 = ((pos.0 - 0.5 - r).ceil() as i32).max(0);
+        let px1 = ((pos.0 - 0.5 + r).floor() as i32).min(self.width as i32 - 1);
+        let py0 = ((pos.1 - 0.5 - r).ceil() as i32).max(self.y0);
+        let py1 = ((pos.1 - 0.5 + r).floor() as i32).min(self.y1 - 1);
+        for py in py0..py1+1 {
+            for px in px0..px1+1 {
+                let w = filter.eval(px as f32 + 0.5 - pos.0, py as f32 + 0.5 - pos.1);
+                if w != 0. {
+                    let p = &mut self.pixels[(px + (py - self.y0) * self.width as i32) as usize];
+                    p.color += color.smul(w);
+                    p.weight += w * count as f32;
+                }
+            }
+        }
+    }
+
+    /* Record the estimate of one eye ray (the mean of its 'count' samples) against the pixel it came from */
+    pub fn add_estimate(&mut self, pixel: u32, estimate: Color3f, count: u32) {
+        let i = pixel as usize - (self.y0 * self.width as i32) as usize;
+        self.pixels[i].add_estimate(estimate.luminance(), count);
+    }
+
+    pub fn merge_into(&self, film: &mut [FilmPixel]) {
+        let offset = (self.y0 * self.width as i32) as usize;
+        for (i, p) in self.pixels.iter().enumerate() {
+            film[offset + i].merge(p);
+        }
+    }
+}
+
+pub fn resolve_film(film: &[FilmPixel], photon_buffer: &mut Vec<Color3f>) {
+    photon_buffer.clear();
+    photon_buffer.extend(film.iter().map(|p| p.resolve()));
+}
+
+/* Pixels whose relative error is still above target */
+pub fn unconverged_pixels(film: &[FilmPixel], target_error: f32) -> Vec<bool> {
+    film.iter().map(|p| p.relative_error() > target_error).collect()
+}
+
+/* Heat map of samples traced per pixel, from blue (fewest) to red (most) */
+pub fn sample_count_map(film: &[FilmPixel]) -> Vec<Color3f> {
+    let max_samples = film.iter().map(|p| p.samples).max().unwrap_or(0).max(1);
+    film.iter().map(|p| {
+        let t = p.samples as f32 / max_samples as f32;
+        Color3f {r: t, g: 0.1, b: 1. - t}
+    }).collect()
+}
+
+/* Each pixel's relative standard error, white at 'max_error' and above */
+pub fn relative_error_map(film: &[FilmPixel], max_error: f32) -> Vec<Color3f> {
+    film.iter().map(|p| {
+        let t = (p.relative_error() / max_error).min(1.);
+        Color3f {r: t, g: t, b: t}
+    }).collect()
+}
+
+#[test]
+fn test_filters() {
+    let mitchell = Filter::Mitchell { radius: 2., b: 1./3., c: 1./3. };
+    assert!((mitchell.eval(0., 0.) - (8./9.)*(8./9.)).abs() < 1e-5);
+    assert_eq!(mitchell.eval(2.5, 0.), 0.);
+    assert!(mitchell.eval(1.5, 0.) < 0.);
+    assert_eq!(Filter::Box(0.5).eval(0.4, -0.4), 1.);
+    assert_eq!(Filter::Box(0.5).eval(0.6, 0.), 0.);
+    assert_eq!(Filter::Tent(1.).eval(0.5, 0.), 0.5);
+    let gaussian = Filter::Gaussian { radius: 1.5, alpha: 2. };
+    assert!(gaussian.eval(0., 0.) > gaussian.eval(0.5, 0.) && gaussian.eval(1.5, 0.) == 0.);
+    let lanczos = Filter::Lanczos { radius: 3., tau: 3. };
+    assert_eq!(lanczos.eval(0., 0.), 1.);
+    assert!(lanczos.eval(1., 0.).abs() < 1e-6 && lanczos.eval(1.5, 0.) < 0.);
+}
+
+#[test]
+fn test_splat() {
+    let white = Color3f {r: 1., g: 1., b: 1.};
+    // box filter keeps each sample in its own pixel
+    let mut tile = FilmTile::new(4, 4, (0, 4), &Filter::Box(0.5));
+    tile.splat(&Filter::Box(0.5), (1.3, 2.9), white.smul(3.), 3);
+    let mut film = vec![FilmPixel::default(); 16];
+    tile.merge_into(&mut film);
+    assert_eq!(film[1 + 2*4].weight, 3.);
+    assert_eq!(film[1 + 2*4].resolve(), white);
+    assert_eq!(film.iter().filter(|p| p.weight != 0.).count(), 1);
+    // a tent straddling two pixel centres splits evenly, and reaches outside the tile's own rows
+    let tent = Filter::Tent(1.);
+    let mut tile = FilmTile::new(4, 4, (2, 3), &tent);
+    tile.splat(&tent, (2., 2.5), white, 1);
+    let mut film = vec![FilmPixel::default(); 16];
+    tile.merge_into(&mut film);
+    assert_eq!(film[1 + 2*4].weight, 0.5);
+    assert_eq!(film[2 + 2*4].weight, 0.5);
+    assert_eq!(film[2 + 1*4].weight, 0.);
+    let mut tile = FilmTile::new(4, 4, (2, 3), &tent);
+    tile.splat(&tent, (2.5, 2.), white, 1);
+    tile.merge_into(&mut film);
+    assert_eq!(film[2 + 1*4].weight, 0.5);
+}
+
+#[test]
+fn test_pixel_variance() {
+    let values = [0.5, 1.5, 1.0, 3.0, 0.0, 2.0];
+    let mut whole = FilmPixel::default();
+    let mut first = FilmPixel::default();
+    let mut second = FilmPixel::default();
+    for (i, &v) in values.iter().enumerate() {
+        whole.add_estimate(v, 4);
+        if i < 2 { first.add_estimate(v, 4) } else { second.add_estimate(v, 4) }
+    }
+    // sample variance of the values is 7/6
+    assert!((whole.mean - 1.3333333).abs() < 1e-5 && (whole.variance() - 1.1666666).abs() < 1e-5);
+    first.merge(&second);
+    assert!((first.mean - whole.mean).abs() < 1e-5 && (first.variance() - whole.variance()).abs() < 1e-5);
+    assert_eq!(first.samples, 24);
+    let mut flat = FilmPixel::default();
+    assert_eq!(flat.relative_error(), f32::INFINITY);
+    flat.add_estimate(0., 1);
+    flat.add_estimate(0., 1);
+    assert_eq!(flat.relative_error(), 0.);
+    let map = relative_error_map(&[flat, FilmPixel::default()], 0.1);
+    assert!(map[0].r == 0. && map[1].r == 1.);
+}
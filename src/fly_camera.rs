@@ -0,0 +1,107 @@
+use std::f32;
+use std::f32::consts::PI;
+use vec3::Vec3;
+use quaternion::Quaternion;
+use raytracer::Camera;
+use projection::Projection;
+
+/* First person controls for moving a camera round a scene in the preview
+ * window: flying along the view direction, strafing, rising and falling,
+ * looking round by yaw and pitch, and zooming by narrowing the field of view.
+ * The camera keeps the orientation it starts with until it's first turned,
+ * when it's rebuilt from yaw and pitch alone, dropping any roll */
+pub struct FlyCamera {
+    pub camera: Camera,
+    // scene units per second
+    pub speed: f32,
+    // radians per pixel of mouse movement
+    pub look_sensitivity: f32,
+    pitch: f32,
+    yaw: f32
+}
+
+// field of view narrows by this factor per step of zoom
+const ZOOM_STEP: f32 = 0.9;
+// stop short of straight up or down, where yaw turns the view about itself
+const MAX_PITCH: f32 = 0.49 * PI;
+
+impl FlyCamera {
+    pub fn new(camera: &Camera) -> FlyCamera {
+        let (pitch, yaw, _) = camera.orientation.to_euler();
+        FlyCamera {
+            camera: camera.clone(),
+            speed: 1.,
+            look_sensitivity: 0.003,
+            pitch: pitch,
+            yaw: yaw
+        }
+    }
+
+    /* Move 'forward' along the view direction, 'right' across it and 'up'
+     * the world's y axis, each in [-1..1] of full speed, for 'dt' seconds */
+    pub fn fly(&mut self, forward: f32, right: f32, up: f32, dt: f32) {
+        let orientation = self.camera.orientation;
+        let step = orientation.vmul(&Vec3 {x: 0., y: 0., z: -1.}).smul(forward) +
+                   orientation.vmul(&Vec3 {x: 1., y: 0., z: 0.}).smul(right) +
+                   Vec3 {x: 0., y: 1., z: 0.}.smul(up);
+        self.camera.position = self.camera.position + step.smul(self.speed * dt);
+    }
+
+    /* Turn the view by a mouse movement of 'dx' pixels right and 'dy' down */
+    pub fn look(&mut self, dx: f32, dy: f32) {
+        self.yaw -= dx * self.look_sensitivity;
+        self.pitch = (self.pitch - dy * self.look_sensitivity).max(-MAX_PITCH).min(MAX_PITCH);
+        self.camera.orientation = Quaternion::from_euler(self.pitch, self.yaw, 0.);
+    }
+
+    /* Zoom in by 'steps', or out when negative, for projections with a field
+     * of view or film size */
+    pub fn zoom(&mut self, steps: f32) {
+        let scale = ZOOM_STEP.powf(steps);
+        let zoom = |fov: &mut f32, max: f32| *fov = (*fov * scale).max(0.01).min(max);
+        match self.camera.projection {
+            Projection::Perspective { ref mut fov, .. } => zoom(fov, 0.95 * PI),
+            Projection::ThinLens(ref mut lens) => zoom(&mut lens.fov, 0.95 * PI),
+            Projection::Fisheye { ref mut fov } => zoom(fov, 2. * PI),
+            Projection::Orthographic { ref mut height } => *height *= scale,
+            _ => {}
+        }
+    }
+}
+
+#[test]
+fn test_fly_camera() {
+    let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-4;
+    let start = Camera {
+        position: Vec3 {x: 0., y: 1., z: 0.},
+        orientation: Quaternion::from_axis_angle(&Vec3 {x: 0., y: 1., z: 0.}, 0.5 * PI),
+        ..Camera::default()
+    };
+    let mut fly = FlyCamera::new(&start);
+    assert!((fly.camera.orientation.dot(&start.orientation).abs() - 1.).abs() < 1e-4);
+    // a rolled or steeply pitched camera is left as it is until it's turned
+    let tilted = Camera {
+        orientation: Quaternion::from_euler(0.5 * PI, 0.3, 0.4),
+        ..Camera::default()
+    };
+    let tilted_fly = FlyCamera::new(&tilted);
+    assert!((tilted_fly.camera.orientation.dot(&tilted.orientation).abs() - 1.).abs() < 1e-6);
+    // turned a quarter left, forwards is -x and right is -z
+    fly.fly(1., 0., 0., 2.);
+    assert!(close(fly.camera.position, Vec3 {x: -2., y: 1., z: 0.}));
+    fly.fly(0., 0.5, 1., 1.);
+    assert!(close(fly.camera.position, Vec3 {x: -2., y: 2., z: -0.5}));
+    // mouse right turns right, and down looks down
+    fly.look(0.25 * PI / fly.look_sensitivity, 0.);
+    let forward = fly.camera.orientation.vmul(&Vec3 {x: 0., y: 0., z: -1.});
+    assert!(close(forward, Vec3 {x: -1., y: 0., z: -1.}.normal()));
+    fly.look(0., 1e6);
+    let forward = fly.camera.orientation.vmul(&Vec3 {x: 0., y: 0., z: -1.});
+    assert!(forward.y < -0.99 && forward.y > -1.);
+    // scrolling up narrows the field of view
+    fly.zoom(2.);
+    match fly.camera.projection {
+        Projection::Perspective { fov, .. } => assert!((fov - 0.5 * PI * 0.81).abs() < 1e-4),
+        _ => panic!()
+    }
+}
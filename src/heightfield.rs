@@ -0,0 +1,248 @@
+use std::f32;
+use std::io;
+use vec3::Vec3;
+use pnm;
+use raytracer::{EPSILON,Ray,IsectFrom};
+
+/* Terrain defined by a regular grid of height samples over the xz plane.
+ * The grid spans origin.x..origin.x+size.x and origin.z..origin.z+size.z,
+ * with normalised heights [0..1] scaled to origin.y..origin.y+size.y.
+ * Each grid cell is split into two triangles. */
+pub struct Heightfield {
+    pub origin: Vec3,
+    pub size: Vec3,
+    pub width: usize,
+    pub depth: usize,
+    heights: Vec<f32>,
+    normals: Vec<Vec3>
+}
+
+impl Heightfield {
+    /* Build from a function of normalised grid position (u, v in [0..1]) returning height in [0..1] */
+    pub fn from_fn<F>(origin: Vec3, size: Vec3, grid: (usize, usize), height_fn: F) -> Heightfield
+        where F: Fn(f32, f32) -> f32 {
+        assert!(grid.0 >= 2 && grid.1 >= 2);
+        let mut heights = Vec::with_capacity(grid.0 * grid.1);
+        for j in 0..grid.1 {
+            for i in 0..grid.0 {
+                let u = i as f32 / (grid.0-1) as f32;
+                let v = j as f32 / (grid.1-1) as f32;
+                heights.push(origin.y + size.y * height_fn(u, v));
+            }
+        }
+        Heightfield::from_heights(origin, size, grid, heights)
+    }
+
+    /* Build from a binary greyscale (P5) or RGB (P6) PNM image, using pixel brightness as height */
+    pub fn from_pnm(filename: &str, origin: Vec3, size: Vec3) -> io::Result<Heightfield> {
+        let (width, height, pixels) = pnm::read_pnm(filename)?;
+        if width < 2 || height < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "heightfield image smaller than 2x2"));
+        }
+        let heights = pixels.iter().map(|c| origin.y + size.y * pnm::brightness(c)).collect();
+        Ok(Heightfield::from_heights(origin, size, (width, height), heights))
+    }
+
+    fn from_heights(origin: Vec3, size: Vec3, grid: (usize, usize), heights: Vec<f32>) -> Heightfield {
+        let mut hf = Heightfield {
+            origin: origin,
+            size: size,
+            width: grid.0,
+            depth: grid.1,
+            heights: heights,
+            normals: Vec::new()
+        };
+        hf.normals = (0..hf.width*hf.depth).map(|n| hf.vertex_normal(n % hf.width, n / hf.width)).collect();
+        hf
+    }
+
+    fn cell_size(&self) -> (f32, f32) {
+        (self.size.x / (self.width-1) as f32, self.size.z / (self.depth-1) as f32)
+    }
+
+    fn height(&self, i: usize, j: usize) -> f32 {
+        self.heights[i + j*self.width]
+    }
+
+    fn vertex(&self, i: usize, j: usize) -> Vec3 {
+        let (cx, cz) = self.cell_size();
+        Vec3 {x: self.origin.x + i as f32 * cx, y: self.height(i, j), z: self.origin.z + j as f32 * cz}
+    }
+
+    // normal from central differences of neighbouring heights (one-sided at the grid edges)
+    fn vertex_normal(&self, i: usize, j: usize) -> Vec3 {
+        let (cx, cz) = self.cell_size();
+        let (i0, i1) = (if i > 0 {i-1} else {i}, if i < self.width-1 {i+1} else {i});
+        let (j0, j1) = (if j > 0 {j-1} else {j}, if j < self.depth-1 {j+1} else {j});
+        let dhdx = (self.height(i1, j) - self.height(i0, j)) / ((i1-i0) as f32 * cx);
+        let dhdz = (self.height(i, j1) - self.height(i, j0)) / ((j1-j0) as f32 * cz);
+        Vec3 {x: -dhdx, y: 1., z: -dhdz}.normal()
+    }
+
+    // ray distances of entry and exit from the bounding box
+    fn clip(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let lo = self.origin;
+        let hi = self.origin + self.size;
+        let mut t0: f32 = 0.;
+        let mut t1: f32 = f32::INFINITY;
+        for &(o, d, l, h) in [(ray.origin.x, ray.dir.x, lo.x, hi.x),
+                              (ray.origin.y, ray.dir.y, lo.y, hi.y),
+                              (ray.origin.z, ray.dir.z, lo.z, hi.z)].iter() {
+            if d == 0. {
+                if o < l || o > h { return None; }
+            } else {
+                let (a, b) = ((l - o) / d, (h - o) / d);
+                t0 = t0.max(a.min(b));
+                t1 = t1.min(a.max(b));
+            }
+        }
+        if t0 <= t1 { Some((t0, t1)) } else { None }
+    }
+
+    /* Distance along ray to the terrain surface, walking the grid cells the ray passes over.
+     * The terrain is solid below its surface, so rays reaching it from beneath start Inside */
+    pub fn intersect(&self, ray: &Ray) -> Option<(IsectFrom, f32)> {
+        let (t_start, t_end) = match self.clip(ray) {
+            Some(span) => span,
+            None => return None
+        };
+        let (cx, cz) = self.cell_size();
+        let max_i = self.width as i32 - 2;
+        let max_j = self.depth as i32 - 2;
+        let start = ray.origin + ray.dir.smul(t_start);
+        let mut i = (((start.x - self.origin.x) / cx).floor() as i32).max(0).min(max_i);
+        let mut j = (((start.z - self.origin.z) / cz).floor() as i32).max(0).min(max_j);
+
+        let step_i = if ray.dir.x > 0. {1} else {-1};
+        let step_j = if ray.dir.z > 0. {1} else {-1};
+        // ray distance between successive cell boundaries, and to the next one
+        let delta_x = (cx / ray.dir.x).abs();
+        let delta_z = (cz / ray.dir.z).abs();
+        let mut next_x = if ray.dir.x != 0. {
+            (self.origin.x + (i + if step_i > 0 {1} else {0}) as f32 * cx - ray.origin.x) / ray.dir.x
+        } else { f32::INFINITY };
+        let mut next_z = if ray.dir.z != 0. {
+            (self.origin.z + (j + if step_j > 0 {1} else {0}) as f32 * cz - ray.origin.z) / ray.dir.z
+        } else { f32::INFINITY };
+
+        let mut t_enter = t_start;
+        loop {
+            let t_exit = next_x.min(next_z).min(t_end);
+            if let Some(hit) = self.intersect_cell(ray, i as usize, j as usize, t_enter, t_exit) {
+                return Some(hit);
+            }
+            if t_exit >= t_end {
+                return None;
+            }
+            if next_x < next_z {
+                i += step_i;
+                next_x += delta_x;
+            } else {
+                j += step_j;
+                next_z += delta_z;
+            }
+            if i < 0 || j < 0 || i > max_i || j > max_j {
+                return None;
+            }
+            t_enter = t_exit;
+        }
+    }
+
+    fn intersect_cell(&self, ray: &Ray, i: usize, j: usize, t_enter: f32, t_exit: f32) -> Option<(IsectFrom, f32)> {
+        let a = self.vertex(i, j);
+        let b = self.vertex(i+1, j);
+        let c = self.vertex(i, j+1);
+        let d = self.vertex(i+1, j+1);
+        // skip cells the ray passes entirely above
+        let ray_min_y = (ray.origin.y + ray.dir.y*t_enter).min(ray.origin.y + ray.dir.y*t_exit);
+        if ray_min_y > a.y.max(b.y).max(c.y.max(d.y)) {
+            return None;
+        }
+        match (ray_triangle_intersect(ray, &a, &b, &d), ray_triangle_intersect(ray, &a, &d, &c)) {
+            (Some(hit1), Some(hit2)) => Some(if hit1.1 < hit2.1 { hit1 } else { hit2 }),
+            (Some(hit), None) | (None, Some(hit)) => Some(hit),
+            (None, None) => None
+        }
+    }
+
+    /* Smooth surface normal at a point on the terrain, interpolated from the vertex normals */
+    pub fn normal_at(&self, pos: &Vec3) -> Vec3 {
+        let (cx, cz) = self.cell_size();
+        let fx = ((pos.x - self.origin.x) / cx).max(0.).min((self.width-1) as f32);
+        let fz = ((pos.z - self.origin.z) / cz).max(0.).min((self.depth-1) as f32);
+        let i = (fx.floor() as usize).min(self.width-2);
+        let j = (fz.floor() as usize).min(self.depth-2);
+        let u = fx - i as f32;
+        let v = fz - j as f32;
+        let na = self.normals[i + j*self.width];
+        let nb = self.normals[i+1 + j*self.width];
+        let nc = self.normals[i + (j+1)*self.width];
+        let nd = self.normals[i+1 + (j+1)*self.width];
+        // barycentric weights within the triangle (a, b, d) or (a, d, c) containing the point
+        if u >= v {
+            (na.smul(1.-u) + nb.smul(u-v) + nd.smul(v)).normal()
+        } else {
+            (na.smul(1.-v) + nd.smul(u) + nc.smul(v-u)).normal()
+        }
+    }
+}
+
+// Moller-Trumbore ray/triangle intersection, returning distance along the ray. Triangles
+// are wound so the ray meets them from above, Outside, when the determinant is negative
+fn ray_triangle_intersect(ray: &Ray, v0: &Vec3, v1: &Vec3, v2: &Vec3) -> Option<(IsectFrom, f32)> {
+    let e1 = *v1 - *v0;
+    let e2 = *v2 - *v0;
+    let p = ray.dir.cross(&e2);
+    let det = e1.dot(&p);
+    if det.abs() < 1e-9 {
+        return None;
+    }
+    let inv_det = 1. / det;
+    let s = ray.origin - *v0;
+    let u = s.dot(&p) * inv_det;
+    if u < 0. || u > 1. {
+        return None;
+    }
+    let q = s.cross(&e1);
+    let v = ray.dir.dot(&q) * inv_det;
+    if v < 0. || u + v > 1. {
+        return None;
+    }
+    let t = e2.dot(&q) * inv_det;
+    if t > EPSILON {
+        Some((if det > 0. { IsectFrom::Inside } else { IsectFrom::Outside }, t))
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_heightfield_flat() {
+    let hf = Heightfield::from_fn(Vec3{x:-1., y:0., z:-1.}, Vec3{x:2., y:1., z:2.}, (9, 9), |_, _| 0.5);
+    let down = Ray {origin: Vec3{x:0.3, y:2., z:-0.2}, dir: Vec3{x:0., y:-1., z:0.}, ..Ray::default()};
+    assert!((hf.intersect(&down).unwrap().1 - 1.5).abs() < 1e-4);
+    assert!((hf.normal_at(&Vec3{x:0.3, y:0.5, z:-0.2}) - Vec3{x:0., y:1., z:0.}).length() < 1e-4);
+    // grazing ray crossing many cells
+    let slant = Ray {origin: Vec3{x:-2., y:0.75, z:0.1}, dir: Vec3{x:1., y:-0.2, z:0.}.normal(), ..Ray::default()};
+    let hit = slant.origin + slant.dir.smul(hf.intersect(&slant).unwrap().1);
+    assert!((hit.x - (-0.75)).abs() < 1e-3 && (hit.y - 0.5).abs() < 1e-3);
+    // ray passing over the terrain
+    let over = Ray {origin: Vec3{x:-2., y:0.75, z:0.}, dir: Vec3{x:1., y:0., z:0.}, ..Ray::default()};
+    assert!(hf.intersect(&over).is_none());
+    // rays meet the surface from outside above it, and inside below it
+    let up = Ray {origin: Vec3{x:0.3, y:0.2, z:-0.2}, dir: Vec3{x:0., y:1., z:0.}, ..Ray::default()};
+    match (hf.intersect(&down), hf.intersect(&up)) {
+        (Some((IsectFrom::Outside, _)), Some((IsectFrom::Inside, t))) => assert!((t - 0.3).abs() < 1e-4),
+        _ => panic!("wrong side of the terrain")
+    }
+}
+
+#[test]
+fn test_heightfield_slope() {
+    // ramp rising along x: height = x
+    let hf = Heightfield::from_fn(Vec3{x:0., y:0., z:0.}, Vec3{x:1., y:1., z:1.}, (5, 5), |u, _| u);
+    let down = Ray {origin: Vec3{x:0.6, y:2., z:0.5}, dir: Vec3{x:0., y:-1., z:0.}, ..Ray::default()};
+    assert!((hf.intersect(&down).unwrap().1 - 1.4).abs() < 1e-4);
+    let n = hf.normal_at(&Vec3{x:0.6, y:0.6, z:0.5});
+    assert!((n - Vec3{x:-1., y:1., z:0.}.normal()).length() < 1e-4);
+}
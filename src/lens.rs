@@ -0,0 +1,447 @@
+use std::f32;
+use std::io;
+use std::io::prelude::*;
+use std::fs::File;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use vec3::Vec3;
+use sampling::concentric_disk;
+use projection::EyeSample;
+use raytracer::Ray;
+use spectrum::BK7_GLASS;
+use pnm;
+
+/* Camera lenses traced surface by surface, after Kolb et al. 1995, "A
+ * Realistic Camera Model for Computer Graphics". Eye rays leave the film for
+ * a point on the rear element and refract through each spherical surface in
+ * turn, so vignetting, distortion and the shape of out of focus highlights all
+ * come from the lens design itself. Lens dimensions are in millimetres */
+
+/* One row of a lens prescription */
+#[derive(Clone, Copy, Debug)]
+pub struct LensSurface {
+    // radius of curvature, positive when the centre is towards the film. 0 for the aperture stop
+    pub radius: f32,
+    // distance along the axis to the next surface towards the film, or from the rear surface to the film
+    pub thickness: f32,
+    // index of refraction between this surface and the next towards the film, 0 or 1 for air
+    pub ior: f32,
+    // clear diameter
+    pub aperture: f32
+}
+
+/* Double Gauss 50mm f/2: US patent 2,673,491, from Smith, "Modern Lens
+ * Design", scaled from 100mm */
+pub const DOUBLE_GAUSS_50MM: &'static str = "
+# radius  thickness  ior    aperture
+29.475    3.76       1.67   25.2
+84.83     0.12       1      25.2
+19.275    4.025      1.67   23
+40.77     3.275      1.699  23
+12.75     5.705      1      18
+0         4.5        0      17.1
+-14.495   1.18       1.603  17
+40.77     6.065      1.658  20
+-20.385   0.19       1      20
+437.065   3.22       1.717  20
+-39.73    0          1      20
+";
+
+#[derive(Clone, Debug)]
+pub struct LensSystem {
+    // front (scene side) to rear
+    pub surfaces: Vec<LensSurface>,
+    // 43.3 for 35mm film
+    pub film_diagonal: f32,
+    // scene units per millimetre
+    pub scale: f32
+}
+
+fn air_if_zero(ior: f32) -> f32 {
+    if ior == 0. { 1. } else { ior }
+}
+
+impl LensSystem {
+    /* Read a prescription: a row per surface from front to rear of radius,
+     * thickness, index of refraction and aperture, with '#' comments. This is
+     * the format of pbrt's lens files */
+    pub fn parse(prescription: &str, film_diagonal: f32, scale: f32) -> io::Result<LensSystem> {
+        let bad_row = |line: &str| io::Error::new(io::ErrorKind::InvalidData, format!("bad lens prescription row '{}'", line));
+        let mut surfaces = Vec::new();
+        for line in prescription.lines() {
+            let row = line.split('#').next().unwrap_or("").trim();
+            if row.is_empty() {
+                continue;
+            }
+            let values: Vec<f32> = match row.split_whitespace().map(|v| v.parse::<f32>()).collect() {
+                Ok(values) => values,
+                Err(_) => return Err(bad_row(line))
+            };
+            if values.len() != 4 {
+                return Err(bad_row(line));
+            }
+            surfaces.push(LensSurface { radius: values[0], thickness: values[1], ior: values[2], aperture: values[3] });
+        }
+        if surfaces.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty lens prescription"));
+        }
+        Ok(LensSystem { surfaces: surfaces, film_diagonal: film_diagonal, scale: scale })
+    }
+
+    pub fn from_file(filename: &str, film_diagonal: f32, scale: f32) -> io::Result<LensSystem> {
+        let mut prescription = String::new();
+        File::open(filename)?.read_to_string(&mut prescription)?;
+        LensSystem::parse(&prescription, film_diagonal, scale)
+    }
+
+    /* The ray leaving the front of the lens, for one from 'origin' on the film
+     * side along 'dir', or None if the housing or aperture stop blocks it. The
+     * film is at z = 0, and the scene towards -z */
+    fn trace_from_film(&self, origin: Vec3, dir: Vec3) -> Option<(Vec3, Vec3)> {
+        let (mut origin, mut dir) = (origin, dir);
+        let mut z = 0.;
+        for i in (0..self.surfaces.len()).rev() {
+            let surface = &self.surfaces[i];
+            z -= surface.thickness;
+            let (t, normal) = if surface.radius == 0. {
+                ((z - origin.z) / dir.z, None)
+            } else {
+                let center = Vec3 {x: 0., y: 0., z: z + surface.radius};
+                let ray = Ray { origin: origin, dir: dir, ..Ray::default() };
+                let (near, far) = match ray.sphere_intersections(&center, surface.radius.abs()) {
+                    Some(intersections) => intersections,
+                    None => return None
+                };
+                // heading for the scene, a surface bulging towards the film is the far side of its sphere
+                let t = if surface.radius < 0. { near } else { far };
+                (t, Some((origin + dir.smul(t) - center).smul(1. / surface.radius.abs())))
+            };
+            if t <= 0. {
+                return None;
+            }
+            let hit = origin + dir.smul(t);
+            if hit.x*hit.x + hit.y*hit.y > 0.25 * surface.aperture * surface.aperture {
+                return None;
+            }
+            if let Some(normal) = normal {
+                // out of the medium behind the surface into the one in front of it
+                let eta_i = air_if_zero(surface.ior);
+                let eta_t = if i > 0 { air_if_zero(self.surfaces[i-1].ior) } else { 1. };
+                let normal = if normal.dot(&dir) > 0. { -normal } else { normal };
+                dir = match dir.refract(&normal, eta_i / eta_t) {
+                    Some(refracted) => refracted,
+                    None => return None
+                };
+            }
+            origin = hit;
+        }
+        Some((origin, dir))
+    }
+
+    /* Distance in front of the film, in millimetres, at which the lens
+     * focuses: where a ray from the middle of the film just off the axis
+     * crosses back to it. None when focused beyond infinity */
+    fn focus_distance_mm(&self) -> Option<f32> {
+        let rear = self.surfaces[self.surfaces.len() - 1];
+        let target = Vec3 {x: 0.01 * rear.aperture, y: 0., z: -rear.thickness};
+        let origin = Vec3 {x: 0., y: 0., z: 0.};
+        match self.trace_from_film(origin, (target - origin).normal()) {
+            Some((o, d)) if o.x * d.x < 0. => Some(-(o.z - o.x / d.x * d.z)),
+            _ => None
+        }
+    }
+
+    /* Move the film so things 'distance' scene units in front of it are sharp */
+    pub fn focus(&mut self, distance: f32) {
+        let target = distance / self.scale;
+        let rear = self.surfaces.len() - 1;
+        let focuses_nearer = |lens: &mut LensSystem, film_distance: f32| {
+            lens.surfaces[rear].thickness = film_distance;
+            lens.focus_distance_mm().map_or(false, |d| d < target)
+        };
+        // the further the film from the lens, the nearer it focuses
+        let mut near = 0.;
+        let mut far: f32 = self.surfaces.iter().map(|s| s.thickness).sum::<f32>() + 1.;
+        for _ in 0..30 {
+            if focuses_nearer(self, far) {
+                break;
+            }
+            near = far;
+            far *= 2.;
+        }
+        for _ in 0..40 {
+            let mid = 0.5 * (near + far);
+            if focuses_nearer(self, mid) {
+                far = mid;
+            } else {
+                near = mid;
+            }
+        }
+        self.surfaces[rear].thickness = far;
+    }
+
+    /* Origin and direction of the eye ray leaving the front of the lens for
+     * 'sample', in the camera's frame as for RayGenerator, or None if the lens
+     * blocks it */
+    pub fn ray(&self, sample: &EyeSample, aspect: f32) -> Option<(Vec3, Vec3)> {
+        let half_height = 0.5 * self.film_diagonal / (1. + aspect*aspect).sqrt();
+        let (u, v) = sample.film;
+        // the lens forms an upside down image, so the film is read rotated by half a turn
+        let film_pos = Vec3 {x: (1. - 2. * u) * aspect * half_height, y: (2. * v - 1.) * half_height, z: 0.};
+        let rear = self.surfaces[self.surfaces.len() - 1];
+        let (dx, dy) = concentric_disk(sample.lens);
+        let target = Vec3 {x: dx * 0.5 * rear.aperture, y: dy * 0.5 * rear.aperture, z: -rear.thickness};
+        self.trace_from_film(film_pos, (target - film_pos).normal())
+            .map(|(origin, dir)| (origin.smul(self.scale), dir))
+    }
+}
+
+/* Shape of a thin lens' opening, which out of focus highlights take on */
+#[derive(Clone)]
+pub enum Aperture {
+    Circle,
+    // regular polygon of 'blades' straight sides in the unit circle, a corner
+    // turned 'rotation' radians anticlockwise from the right
+    Polygon { blades: u32, rotation: f32 },
+    Mask(Arc<ApertureMask>)
+}
+
+/* Aperture drawn as an image over the square round the lens' circle: white
+ * is open, black blocks light, and grey lets part of it through */
+#[derive(Clone, Debug)]
+pub struct ApertureMask {
+    pub width: usize,
+    pub height: usize,
+    // row by row from the top, in [0..1]
+    transmission: Vec<f32>
+}
+
+impl ApertureMask {
+    /* Build from a function of position (x, y in [-1..1], y up) returning transmission */
+    pub fn from_fn<F>(size: (usize, usize), transmission_fn: F) -> ApertureMask
+        where F: Fn(f32, f32) -> f32 {
+        let mut transmission = Vec::with_capacity(size.0 * size.1);
+        for j in 0..size.1 {
+            for i in 0..size.0 {
+                let x = 2. * (i as f32 + 0.5) / size.0 as f32 - 1.;
+                let y = 1. - 2. * (j as f32 + 0.5) / size.1 as f32;
+                transmission.push(transmission_fn(x, y).max(0.).min(1.));
+            }
+        }
+        ApertureMask { width: size.0, height: size.1, transmission: transmission }
+    }
+
+    /* Build from a binary greyscale (P5) or RGB (P6) PNM image, using pixel brightness as transmission */
+    pub fn from_pnm(filename: &str) -> io::Result<ApertureMask> {
+        let (width, height, pixels) = pnm::read_pnm(filename)?;
+        if width == 0 || height == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "empty aperture image"));
+        }
+        let transmission = pixels.iter().map(|c| pnm::brightness(c)).collect();
+        Ok(ApertureMask { width: width, height: height, transmission: transmission })
+    }
+}
+
+impl Aperture {
+    /* Point on the opening in the unit disk, for 'u' uniform in [0..1)^2, or
+     * None where the point is blocked. Points are uniform over the opening, so
+     * narrower shapes let less light through */
+    pub fn sample(&self, u: (f32, f32)) -> Option<(f32, f32)> {
+        match *self {
+            Aperture::Circle => Some(concentric_disk(u)),
+            Aperture::Polygon { blades, rotation } => {
+                if blades < 3 {
+                    return Some(concentric_disk(u));
+                }
+                // pick a triangle between the middle and one side, then a point uniformly in it
+                let n = blades as f32;
+                let side = (u.0 * n).floor().min(n - 1.);
+                let s = (u.0 * n - side).sqrt();
+                let corner = |k: f32| {
+                    let angle = rotation + 2. * PI * k / n;
+                    (angle.cos(), angle.sin())
+                };
+                let (a, b) = (corner(side), corner(side + 1.));
+                Some((s * (a.0 + (b.0 - a.0) * u.1), s * (a.1 + (b.1 - a.1) * u.1)))
+            }
+            Aperture::Mask(ref mask) => {
+                let (x, y) = (u.0 * mask.width as f32, u.1 * mask.height as f32);
+                let (i, j) = ((x as usize).min(mask.width - 1), (y as usize).min(mask.height - 1));
+                // a grey pixel is open across that fraction of its width
+                if x - i as f32 >= mask.transmission[i + j * mask.width] {
+                    return None;
+                }
+                Some((2. * u.0 - 1., 1. - 2. * u.1))
+            }
+        }
+    }
+}
+
+/* Wavelength, the sodium D line, at which a thin lens' field of view and
+ * focus distance are given. The F and C hydrogen lines below bracket the
+ * visible spectrum in the usual measure of a glass' dispersion */
+const DESIGN_WAVELENGTH: f32 = 587.6;
+const F_LINE: f32 = 486.1;
+const C_LINE: f32 = 656.3;
+
+/* How much stronger a glass lens is at 'lambda' than at the design
+ * wavelength, relative to the difference between the F and C lines: for
+ * crown glass about 1.7 at 400nm, 0 in the yellow and -0.5 at 700nm */
+fn dispersion(lambda: f32) -> f32 {
+    if lambda <= 0. {
+        return 0.;
+    }
+    let n = |lambda: f32| BK7_GLASS.at(lambda);
+    (n(lambda) - n(DESIGN_WAVELENGTH)) / (n(F_LINE) - n(C_LINE))
+}
+
+/* Ideal lens of no thickness in front of a pinhole's film, focusing at one
+ * distance and blurring everything nearer or further through the shape of its
+ * aperture. Cat's eye vignetting and chromatic aberration are optional; the
+ * latter follows the dispersion of crown glass and shows only in spectral
+ * renders, where each wavelength is imaged on its own */
+#[derive(Clone)]
+pub struct ThinLens {
+    // vertical field of view, radians
+    pub fov: f32,
+    // radius of the opening, in scene units. 0 for a pinhole
+    pub aperture_radius: f32,
+    // distance in front of the lens that is sharp
+    pub focus_distance: f32,
+    pub aperture: Aperture,
+    // how much the lens barrel cuts off the aperture towards the edges of the
+    // film, making highlights there cat's eye shaped. 0 for none, 1 to close
+    // it completely in the corners
+    pub cats_eye: f32,
+    // fraction by which blue light (F line) focuses nearer than red (C line)
+    pub longitudinal_aberration: f32,
+    // fraction by which the image in red light (C line) is magnified over blue (F line)
+    pub lateral_aberration: f32
+}
+
+impl ThinLens {
+    pub fn new(fov: f32, aperture_radius: f32, focus_distance: f32) -> ThinLens {
+        ThinLens {
+            fov: fov,
+            aperture_radius: aperture_radius,
+            focus_distance: focus_distance,
+            aperture: Aperture::Circle,
+            cats_eye: 0.,
+            longitudinal_aberration: 0.,
+            lateral_aberration: 0.
+        }
+    }
+
+    /* Origin and direction of the eye ray for 'sample', in the camera's frame
+     * as for RayGenerator, or None where the aperture is blocked */
+    pub fn ray(&self, sample: &EyeSample, aspect: f32) -> Option<(Vec3, Vec3)> {
+        let dispersion = dispersion(sample.wavelength);
+        let (u, v) = sample.film;
+        let (fx, fy) = ((2. * u - 1.) * aspect, 1. - 2. * v);
+        let (lx, ly) = match self.aperture.sample(sample.lens) {
+            Some(point) => point,
+            None => return None
+        };
+        if self.cats_eye > 0. {
+            // the barrel's opening, as big as the aperture, slides off it
+            // towards the edges of the film
+            let offset = 2. * self.cats_eye / (aspect*aspect + 1.).sqrt();
+            let (bx, by) = (lx - fx * offset, ly - fy * offset);
+            if bx*bx + by*by > 1. {
+                return None;
+            }
+        }
+        let half_height = (0.5 * self.fov).tan() * (1. + self.lateral_aberration * dispersion);
+        let focus_distance = self.focus_distance * (1. - self.longitudinal_aberration * dispersion);
+        let focus = Vec3 {x: fx * half_height, y: fy * half_height, z: -1.}.smul(focus_distance);
+        let lens_pos = Vec3 {x: lx * self.aperture_radius, y: ly * self.aperture_radius, z: 0.};
+        Some((lens_pos, (focus - lens_pos).normal()))
+    }
+
+    /* Whether rays depend on the sample's wavelength */
+    pub fn is_dispersive(&self) -> bool {
+        self.longitudinal_aberration != 0. || self.lateral_aberration != 0.
+    }
+}
+
+#[test]
+fn test_lens_system() {
+    assert!(LensSystem::parse("# just a comment\n", 43.3, 0.001).is_err());
+    assert!(LensSystem::parse("1 2 3\n", 43.3, 0.001).is_err());
+    let mut lens = LensSystem::parse(DOUBLE_GAUSS_50MM, 43.3, 0.001).unwrap();
+    assert_eq!(lens.surfaces.len(), 11);
+    assert_eq!(lens.surfaces[5].radius, 0.);
+
+    // focused at infinity the film sits at about the back focal distance
+    lens.focus(1e6);
+    let back_focus = lens.surfaces[10].thickness;
+    assert!(back_focus > 25. && back_focus < 50., "{}", back_focus);
+    // and further back to focus nearer
+    lens.focus(1.);
+    assert!(lens.surfaces[10].thickness > back_focus);
+    assert!((lens.focus_distance_mm().unwrap() - 1000.).abs() < 1.);
+
+    // rays from the middle of the film through all of the aperture meet about 1m away
+    for &u in [(0.5, 0.5), (0.3, 0.6), (0.5, 0.8), (0.2, 0.5)].iter() {
+        let (origin, dir) = lens.ray(&EyeSample { film: (0.5, 0.5), lens: u, wavelength: 0. }, 1.5).unwrap();
+        let at_focus = origin + dir.smul((-1. - origin.z) / dir.z);
+        assert!(at_focus.x.abs() < 2e-3 && at_focus.y.abs() < 2e-3, "{:?} {:?}", u, at_focus);
+    }
+    // the image comes out the right way up
+    let (_, left) = lens.ray(&EyeSample { film: (0.2, 0.5), lens: (0.5, 0.5), wavelength: 0. }, 1.5).unwrap();
+    let (_, up) = lens.ray(&EyeSample { film: (0.5, 0.2), lens: (0.5, 0.5), wavelength: 0. }, 1.5).unwrap();
+    assert!(left.x < 0. && up.y > 0. && left.z < 0.);
+    // corners of the film see through less of the aperture
+    let open = |film: (f32, f32)| (0..20).flat_map(|i| (0..20).map(move |j| (i, j)))
+        .filter(|&(i, j)| lens.ray(&EyeSample { film: film, lens: ((i as f32 + 0.5) / 20., (j as f32 + 0.5) / 20.), wavelength: 0. }, 1.5).is_some())
+        .count();
+    assert!(open((0.02, 0.02)) < open((0.5, 0.5)));
+}
+
+#[test]
+fn test_thin_lens() {
+    let grid = |n: usize| (0..n).flat_map(move |i| (0..n).map(move |j| ((i as f32 + 0.5) / n as f32, (j as f32 + 0.5) / n as f32)));
+    let mut lens = ThinLens::new(0.5 * PI, 0.1, 2.);
+    let at = |film: (f32, f32), u: (f32, f32), wavelength: f32| EyeSample { film: film, lens: u, wavelength: wavelength };
+    // rays through all of the aperture meet at the focus distance
+    for u in grid(4) {
+        let (origin, dir) = lens.ray(&at((0.75, 0.5), u, 0.), 1.).unwrap();
+        let at_focus = origin + dir.smul((-2. - origin.z) / dir.z);
+        assert!((at_focus - Vec3 {x: 1., y: 0., z: -2.}).length() < 1e-4, "{:?}", at_focus);
+    }
+
+    // hexagonal bokeh stays within the hexagon, and covers its corners
+    let hexagon = Aperture::Polygon { blades: 6, rotation: 0. };
+    let points: Vec<(f32, f32)> = grid(30).map(|u| hexagon.sample(u).unwrap()).collect();
+    for k in 0..6 {
+        let angle = (k as f32 + 0.5) * PI / 3.;
+        assert!(points.iter().all(|p| p.0 * angle.cos() + p.1 * angle.sin() <= (PI / 6.).cos() + 1e-4));
+    }
+    assert!(points.iter().any(|p| p.0 > 0.9 && p.1.abs() < 0.05));
+    // a mask open on the left only lets through the left
+    let half = Aperture::Mask(Arc::new(ApertureMask::from_fn((8, 8), |x, _| if x < 0. { 1. } else { 0. })));
+    let open: Vec<(f32, f32)> = grid(20).filter_map(|u| half.sample(u)).collect();
+    assert_eq!(open.len(), 200);
+    assert!(open.iter().all(|p| p.0 < 0.));
+
+    // cat's eye vignetting closes the corners but not the middle
+    lens.cats_eye = 1.;
+    let open = |lens: &ThinLens, film: (f32, f32)| grid(20).filter(|&u| lens.ray(&at(film, u, 0.), 1.5).is_some()).count();
+    assert_eq!(open(&lens, (0.5, 0.5)), 400);
+    assert!(open(&lens, (0.3, 0.5)) < 400);
+    assert_eq!(open(&lens, (0., 0.)), 0);
+
+    // blue focuses nearer than red, and is imaged smaller
+    lens = ThinLens { longitudinal_aberration: 0.05, lateral_aberration: 0.02, ..ThinLens::new(0.5 * PI, 0.1, 2.) };
+    assert!(lens.is_dispersive() && !ThinLens::new(0.5 * PI, 0.1, 2.).is_dispersive());
+    let focus_z = |lambda: f32| {
+        let (origin, dir) = lens.ray(&at((0.5, 0.5), (1., 0.5), lambda), 1.).unwrap();
+        origin.z - origin.x / dir.x * dir.z
+    };
+    assert!(-focus_z(450.) < -focus_z(650.));
+    assert!((focus_z(DESIGN_WAVELENGTH) + 2.).abs() < 1e-4);
+    let edge_x = |lambda: f32| lens.ray(&at((1., 0.5), (0.5, 0.5), lambda), 1.).unwrap().1.x;
+    assert!(edge_x(450.) > edge_x(650.));
+    // the F to C line difference in focus is the longitudinal aberration
+    assert!(((focus_z(F_LINE) - focus_z(C_LINE)) / 2. - 0.05).abs() < 1e-3);
+}
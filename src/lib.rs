@@ -3,50 +3,71 @@ extern crate rand;
 extern crate time;
 extern crate crossbeam;
 
-use std::mem;
-use std::slice;
+use std::f32;
 use std::fs::File;
 use std::io::prelude::*;
 use sdl2::rect::Rect;
-use sdl2::pixels::Color;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use rand::Rng; // why did i need this for rng.gen?
+use sdl2::pixels::{Color,PixelFormatEnum};
+use sdl2::render::{Renderer,Texture};
+use sdl2::event::{Event,WindowEventId};
+use sdl2::keyboard::{Keycode,Scancode};
+use sdl2::mouse::Mouse;
 
 pub mod quaternion;
 pub mod vec3;
 pub mod color3f;
 pub mod raytracer;
 pub mod shaders;
-use quaternion::Quaternion;
-use vec3::Vec3;
+pub mod heightfield;
+pub mod pnm;
+pub mod animation;
+pub mod sampling;
+pub mod sampler;
+pub mod film;
+pub mod light;
+pub mod spectrum;
+pub mod volume;
+pub mod atmosphere;
+pub mod textures;
+pub mod projection;
+pub mod lens;
+pub mod fly_camera;
+pub mod material_editor;
+pub mod viewer;
+pub mod progress;
 use color3f::Color3f;
-use raytracer::{VacuumAction,EPSILON,RenderConfig,SceneObj,Primitive,Material,Ray,RayIsect,Scene,Camera,
+use sampler::{Sampler,EYE_RAY_DIMENSIONS};
+use spectrum::{SpectrumToRgb,HERO_WAVELENGTHS,hero_wavelengths,rgb_to_spectrum,sample_wavelength};
+use projection::{CubemapLayout,EyeSample,cubemap_faces};
+use fly_camera::FlyCamera;
+use material_editor::MaterialEditor;
+use viewer::View;
+use progress::{CancelToken,Progress};
+use film::{FilmPixel,FilmTile,resolve_film,unconverged_pixels,sample_count_map,relative_error_map};
+use raytracer::{VacuumAction,EPSILON,RenderConfig,AdaptiveConfig,SceneObj,Primitive,Material,Ray,RayIsect,Scene,Camera,
 Path,IsectFrom,MAX_BOUNCES};
 
 fn ray_primitive_intersects<'a>(ray: &Ray, scene_obj: &'a SceneObj) -> Option<RayIsect<'a>> {
-    match scene_obj.prim {
+    match primitive_intersects(ray, &scene_obj.prim) {
+        Some((from, dist)) => Some(RayIsect{from: from, dist: dist, scene_obj: &scene_obj, ray: ray.clone()}),
+        None => None
+    }
+}
+
+fn primitive_intersects(ray: &Ray, prim: &Primitive) -> Option<(IsectFrom, f32)> {
+    match *prim {
         Primitive::Sphere(origin, radius) => {
-            let v = ray.origin - origin;
-            let b = -(v.dot(&ray.dir));
-            let sq_det = (b*b) - v.dot(&v) + radius*radius;
-            if sq_det > 0. {
-                let det = sq_det.sqrt();
-                let i1 = b - det;
-                let i2 = b + det;
-                if i2 > 0. {
+            match ray.sphere_intersections(&origin, radius) {
+                Some((i1, i2)) if i2 > 0. => {
                     if i1 < 0. {
                         // inside! XXX don't need until we have refraction
-                        Some(RayIsect{from: IsectFrom::Inside, dist:i2, scene_obj:&scene_obj, ray:ray.clone()})
+                        Some((IsectFrom::Inside, i2))
                     } else {
                         // outside
-                        Some(RayIsect{from: IsectFrom::Outside, dist:i1, scene_obj:&scene_obj, ray:ray.clone()})
+                        Some((IsectFrom::Outside, i1))
                     }
-                } else {
-                    None
                 }
-            } else {
-                None
+                _ => None
             }
         }
         Primitive::Triangle(a, b, c) => {
@@ -62,7 +83,7 @@ fn ray_primitive_intersects<'a>(ray: &Ray, scene_obj: &'a SceneObj) -> Option<Ra
                (v0d < 0. && v1d < 0. && v2d < 0.) {
                 let dist = nominator / ray.dir.dot(&n);
                 if dist > EPSILON {
-                    Some(RayIsect{from: IsectFrom::Outside, dist:dist, scene_obj: &scene_obj, ray: ray.clone()})
+                    Some((IsectFrom::Outside, dist))
                 } else {
                     None
                 }
@@ -75,7 +96,7 @@ fn ray_primitive_intersects<'a>(ray: &Ray, scene_obj: &'a SceneObj) -> Option<Ra
             if denom.abs() > EPSILON {
                 let t: f32 = (center - ray.origin).dot(&normal) / denom;
                 if t >= 0. {
-                    Some(RayIsect{from: IsectFrom::Outside, dist:t, scene_obj: &scene_obj, ray: ray.clone()})
+                    Some((IsectFrom::Outside, t))
                 } else {
                     None
                 }
@@ -83,6 +104,12 @@ fn ray_primitive_intersects<'a>(ray: &Ray, scene_obj: &'a SceneObj) -> Option<Ra
                 None
             }
         }
+        Primitive::Heightfield(ref hf) => hf.intersect(ray),
+        Primitive::Moving(ref prim, ref motion) => {
+            // intersect in the primitive's frame at the ray's time
+            let local_ray = Ray {origin: ray.origin - motion.offset_at(ray.time), dir: ray.dir, ..*ray};
+            primitive_intersects(&local_ray, prim)
+        }
         Primitive::ScatterEvent => None
     }
 }
@@ -90,7 +117,8 @@ fn ray_primitive_intersects<'a>(ray: &Ray, scene_obj: &'a SceneObj) -> Option<Ra
 fn find_first_intersection<'a>(ray: &Ray, scene: &'a Scene) -> Option<RayIsect<'a>> {
     let mut nearest: Option<RayIsect> = None;
 
-    for ref obj in &scene.objs {
+    // the surfaces of media are invisible
+    for ref obj in scene.objs.iter().filter(|obj| obj.medium.is_none()) {
         match ray_primitive_intersects(&ray, &obj) {
             Some(isect) => {
                 if nearest.is_some() {
@@ -110,8 +138,169 @@ fn find_first_intersection<'a>(ray: &Ray, scene: &'a Scene) -> Option<RayIsect<'
     nearest
 }
 
-fn make_ray_scatter_path<'a>(ray: &Ray, scene: &'a Scene, rng: &mut rand::ThreadRng, path: &mut Path<'a>) {
-    match find_first_intersection(ray, &scene) {
+/* Eye ray through the middle of 'pixel' of an image of 'image_size', and of the camera's lens */
+fn pixel_center_ray(camera: &Camera, image_size: (u32, u32), pixel: (u32, u32)) -> Option<Ray> {
+    let (fw, fh) = (image_size.0 as f32, image_size.1 as f32);
+    let sample = EyeSample { film: ((pixel.0 as f32 + 0.5) / fw, (pixel.1 as f32 + 0.5) / fh), lens: (0.5, 0.5), wavelength: 0. };
+    camera.projection.eye_ray(&sample, fw / fh).map(|(origin, dir)| Ray {
+        origin: camera.position + camera.orientation.vmul(&origin),
+        dir: camera.orientation.vmul(&dir),
+        time: camera.shutter_open,
+        ..Ray::default()
+    })
+}
+
+/* Index in scene.objs of the object seen at 'pixel' of an image of 'image_size' */
+pub fn pick_object(camera: &Camera, scene: &Scene, image_size: (u32, u32), pixel: (u32, u32)) -> Option<usize> {
+    pixel_center_ray(camera, image_size, pixel)
+        .and_then(|ray| find_first_intersection(&ray, scene).map(|isect| isect.scene_obj as *const SceneObj))
+        .and_then(|hit| scene.objs.iter().position(|obj| obj as *const SceneObj == hit))
+}
+
+/* A first hit view (see View::is_first_hit) of an image of 'image_size', in [0..1] */
+pub fn first_hit_view(view: View, camera: &Camera, scene: &Scene, image_size: (u32, u32)) -> Vec<Color3f> {
+    let width = image_size.0;
+    let hits: Vec<Option<RayIsect>> = (0..image_size.0 * image_size.1)
+        .map(|n| pixel_center_ray(camera, image_size, (n % width, n / width)).and_then(|ray| find_first_intersection(&ray, scene)))
+        .collect();
+    let max_dist = hits.iter().filter_map(|hit| hit.map(|isect| isect.dist)).fold(0., f32::max);
+    hits.iter().map(|hit| match *hit {
+        None => Color3f::black(),
+        Some(isect) => match view {
+            View::Normal => {
+                let n = isect.normal();
+                Color3f {r: 0.5 * (n.x + 1.), g: 0.5 * (n.y + 1.), b: 0.5 * (n.z + 1.)}
+            }
+            View::Albedo => (isect.scene_obj.mat.color_program)(&isect).transmissive.clamp(0., 1.),
+            View::Depth => {
+                let t = 1. - isect.dist / max_dist;
+                Color3f {r: t, g: t, b: t}
+            }
+            _ => Color3f::black()
+        }
+    }).collect()
+}
+
+fn find_first_light_hit(ray: &Ray, scene: &Scene) -> Option<(f32, Color3f)> {
+    let mut nearest: Option<(f32, Color3f)> = None;
+
+    for light in &scene.lights {
+        if let Some((dist, radiance)) = light.intersect(ray) {
+            if nearest.map_or(true, |(nearest_dist, _)| dist < nearest_dist) {
+                nearest = Some((dist, radiance));
+            }
+        }
+    }
+    nearest
+}
+
+/* The stretch of 'ray' before max_dist that lies inside closed primitive 'prim' */
+fn segment_inside(ray: &Ray, prim: &Primitive, max_dist: f32) -> Option<(f32, f32)> {
+    let (start, end) = match primitive_intersects(ray, prim) {
+        Some((IsectFrom::Inside, exit)) => (0., exit),
+        Some((IsectFrom::Outside, entry)) => {
+            let inside_ray = Ray {origin: ray.origin + ray.dir.smul(entry + EPSILON), ..*ray};
+            match primitive_intersects(&inside_ray, prim) {
+                Some((IsectFrom::Inside, exit)) => (entry, entry + EPSILON + exit),
+                _ => return None
+            }
+        }
+        None => return None
+    };
+    if start < max_dist { Some((start, end.min(max_dist))) } else { None }
+}
+
+/* Delta tracked collision with the nearest particle of any medium along 'ray' before max_dist */
+fn find_first_medium_collision<'a>(ray: &Ray, max_dist: f32, scene: &'a Scene, rng: &mut Sampler) -> Option<RayIsect<'a>> {
+    let mut nearest: Option<RayIsect> = None;
+
+    for obj in &scene.objs {
+        if let Some(ref medium) = obj.medium {
+            let max_dist = nearest.map_or(max_dist, |isect| isect.dist);
+            if let Some((start, end)) = segment_inside(ray, &obj.prim, max_dist) {
+                let segment_ray = Ray {origin: ray.origin + ray.dir.smul(start), ..*ray};
+                if let Some(t) = medium.sample_collision(&segment_ray, end - start, rng) {
+                    nearest = Some(RayIsect {ray: *ray, dist: start + t, from: IsectFrom::Inside, scene_obj: obj});
+                }
+            }
+        }
+    }
+    nearest
+}
+
+/* Fraction of light getting 'dist' along 'ray': 0 if a surface is in the
+ * way, otherwise the transmittance of any media, by ratio tracking. Shadow
+ * rays pass through the bounds of vacuum program media they start inside */
+fn transmittance(ray: &Ray, dist: f32, scene: &Scene, rng: &mut Sampler) -> f32 {
+    let mut transmittance = 1.;
+
+    for obj in &scene.objs {
+        match obj.medium {
+            Some(ref medium) => {
+                if let Some((start, end)) = segment_inside(ray, &obj.prim, dist) {
+                    let segment_ray = Ray {origin: ray.origin + ray.dir.smul(start), ..*ray};
+                    transmittance *= medium.transmittance(&segment_ray, end - start, rng);
+                }
+            }
+            None => {
+                match primitive_intersects(ray, &obj.prim) {
+                    Some((IsectFrom::Inside, _)) if obj.mat.vacuum_program.is_some() => {},
+                    Some((_, d)) if d < dist - EPSILON => return 0.,
+                    _ => {}
+                }
+            }
+        }
+    }
+    transmittance
+}
+
+/* Light arriving at an isect straight from one of the scene's lights, picked at random */
+fn sample_direct_light(isect: &RayIsect, scene: &Scene, rng: &mut Sampler) -> Color3f {
+    if !isect.samples_lights() || scene.lights.is_empty() {
+        return Color3f::black();
+    }
+    let num_lights = scene.lights.len();
+    let light = &scene.lights[((rng.next_1d() * num_lights as f32) as usize).min(num_lights - 1)];
+    let origin = isect.hit_pos() + isect.normal().smul(EPSILON);
+
+    match light.sample(&origin, rng.next_2d()) {
+        Some(sample) => {
+            let reflectance = isect.light_response(&sample.dir);
+            let shadow_ray = Ray {origin: origin, dir: sample.dir, ..isect.ray};
+            if reflectance > 0. {
+                // each light is picked 1 / num_lights of the time
+                sample.contribution.smul(reflectance * num_lights as f32 * transmittance(&shadow_ray, sample.dist, scene, rng))
+            } else {
+                Color3f::black()
+            }
+        }
+        None => Color3f::black()
+    }
+}
+
+fn make_ray_scatter_path<'a>(ray: &Ray, scene: &'a Scene, rng: &mut Sampler, path: &mut Path<'a>) {
+    path.single_wavelength |= ray.single_wavelength;
+    // a path only sees lights directly if its last isect didn't already sample them
+    let light_hit = if path.num_bounces == 0 ||
+                       !path.isects[path.num_bounces as usize - 1].samples_lights() {
+        find_first_light_hit(ray, scene)
+    } else {
+        None
+    };
+
+    let surface_isect = match find_first_intersection(ray, &scene) {
+        Some(isect) if light_hit.map_or(true, |(dist, _)| isect.dist < dist) => Some(isect),
+        _ => None
+    };
+    let max_dist = match (surface_isect, light_hit) {
+        (Some(isect), _) => isect.dist,
+        (None, Some((dist, _))) => dist,
+        (None, None) => f32::INFINITY
+    };
+    // particles in media may get in the way first
+    let medium_collision = find_first_medium_collision(ray, max_dist, scene, rng);
+
+    match medium_collision.or(surface_isect) {
         Some(mut isect) => {
             // if vacuum program causes scatter event then
             // switch out this isect with scatter isect
@@ -125,10 +314,11 @@ fn make_ray_scatter_path<'a>(ray: &Ray, scene: &'a Scene, rng: &mut rand::Thread
                 }
             };
             path.isects[path.num_bounces as usize] = isect.clone();
+            path.direct_light[path.num_bounces as usize] = sample_direct_light(&isect, scene, rng);
             path.num_bounces += 1;
             if path.num_bounces < MAX_BOUNCES as i32 {
                 // call material's path_program to see what our next ray will be
-                match (isect.scene_obj.mat.path_program)(&isect, rng) {
+                match isect.next_ray(rng) {
                     Some(next_ray) => {
                         make_ray_scatter_path(&next_ray, scene, rng, path);
                     },
@@ -136,12 +326,12 @@ fn make_ray_scatter_path<'a>(ray: &Ray, scene: &'a Scene, rng: &mut rand::Thread
                 }
             }
         },
-        None => ()
+        _ => path.light_hit = light_hit.map(|(_, radiance)| radiance)
     }
 }
 
 fn collect_light_from_path(path: &mut Path) -> Color3f {
-    let mut color = Color3f::default();
+    let mut color = path.light_hit.unwrap_or(Color3f::default());
 
     for i in (0..path.num_bounces as usize).rev() {
         let r = {
@@ -149,12 +339,12 @@ fn collect_light_from_path(path: &mut Path) -> Color3f {
             if i == 0 && path.first_isect_color.is_some() {
                 path.first_isect_color.unwrap()
             } else {
-                let r2 = (path.isects[i].scene_obj.mat.color_program)(&path.isects[i]);
+                let r2 = path.isects[i].color();
                 if i == 0 { path.first_isect_color = Some(r2); }
                 r2
             }
         };
-        color = r.emissive + (color * r.transmissive);
+        color = r.emissive + ((path.direct_light[i] + color) * r.transmissive);
         
         /*
         // wrong, since it makes all surfaces have diffuse BDRF
@@ -177,105 +367,203 @@ fn collect_light_from_path(path: &mut Path) -> Color3f {
     color
 }
 
-fn path_trace_rays(config: &RenderConfig, rays: &Vec<Ray>, scene: &Scene,
-                   rng: &mut rand::ThreadRng, photon_buffer: &mut [Color3f]) {
+/* As collect_light_from_path, but for each of the path's wavelengths in turn,
+ * with colours upsampled to spectra and the result converted back to RGB */
+fn collect_spectral_light_from_path(path: &Path, hero: f32, to_rgb: &SpectrumToRgb) -> Color3f {
+    let lambdas = hero_wavelengths(hero);
+    let count = if path.single_wavelength { 1 } else { HERO_WAVELENGTHS };
+    let mut values = [0.; HERO_WAVELENGTHS];
+
+    for w in 0..count {
+        let lambda = lambdas[w];
+        let mut value = path.light_hit.map_or(0., |c| rgb_to_spectrum(&c, lambda));
+        for i in (0..path.num_bounces as usize).rev() {
+            // colour programs see the wavelength they are evaluated at
+            let mut isect = path.isects[i];
+            isect.ray.wavelength = lambda;
+            let r = isect.color();
+            value = rgb_to_spectrum(&r.emissive, lambda) +
+                (rgb_to_spectrum(&path.direct_light[i], lambda) + value) * rgb_to_spectrum(&r.transmissive, lambda);
+        }
+        values[w] = value;
+    }
+    to_rgb.to_rgb(&lambdas[..count], &values[..count])
+}
+
+fn path_trace_rays(config: &RenderConfig, rays: &Vec<(Option<Ray>, (f32, f32))>, scene: &Scene, first_pixel: u32, first_sample: u32,
+                   active: Option<&[bool]>, cancel: Option<&CancelToken>, rng: &mut Sampler, tile: &mut FilmTile) {
 
     let mut path = Path {
         num_bounces: 0,
         isects: [RayIsect{from: IsectFrom::Outside, ray:Ray::default(), dist: 0., scene_obj: &scene.objs[0]}; MAX_BOUNCES],
-        first_isect_color: None
+        first_isect_color: None,
+        direct_light: [Color3f::black(); MAX_BOUNCES],
+        light_hit: None,
+        single_wavelength: false
     };
+    let to_rgb = SpectrumToRgb::new();
+    // how far light gets through chromatic media depends on its wavelength, so
+    // paths only estimate the hero wavelength they were tracked at
+    let chromatic_media = config.spectral &&
+        scene.objs.iter().any(|obj| obj.medium.as_ref().map_or(false, |medium| medium.is_chromatic()));
     // could have initted unsafely (and maybe unwisely) like this also:
     // unsafe { path = std::mem::uninitialized(); }
 
     for i in 0..rays.len() {
+        // a cancelled render leaves the rest of its pixels a sample short
+        if cancel.map_or(false, |cancel| cancel.is_cancelled()) {
+            break;
+        }
+        // adaptive sampling leaves converged pixels alone
+        if let Some(active) = active {
+            if !active[first_pixel as usize + i] {
+                continue;
+            }
+        }
         // trace first path and collect its light contribution
         path.num_bounces = 0;
         path.first_isect_color = None;
+        path.light_hit = None;
+        path.single_wavelength = chromatic_media;
 
-        make_ray_scatter_path(&rays[i], scene, rng, &mut path);
-        photon_buffer[i] += collect_light_from_path(&mut path);
+        let (eye_ray, film_pos) = match rays[i] {
+            (Some(ref eye_ray), film_pos) => (eye_ray, film_pos),
+            // off the edge of the projection: black
+            (None, film_pos) => {
+                tile.splat(&config.filter, film_pos, Color3f::black(), config.samples_per_first_isect);
+                tile.add_estimate(first_pixel + i as u32, Color3f::black(), config.samples_per_first_isect);
+                continue;
+            }
+        };
+        // continue the sample the eye ray was generated from
+        rng.start_pixel_sample(first_pixel + i as u32, first_sample);
+        rng.skip_dimensions(EYE_RAY_DIMENSIONS);
+        make_ray_scatter_path(eye_ray, scene, rng, &mut path);
+        let mut color = if config.spectral {
+            collect_spectral_light_from_path(&path, eye_ray.wavelength, &to_rgb)
+        } else {
+            collect_light_from_path(&mut path)
+        };
         // now reuse the first isect for a few more paths! (great optimisation)
         if path.num_bounces > 0 {
             let first_isect = path.isects[0].clone();
-            for _ in 0..(config.samples_per_first_isect-1) {
+            for j in 1..config.samples_per_first_isect {
                 path.num_bounces = 1;
-                if let Some(next_ray) = (first_isect.scene_obj.mat.path_program)(&first_isect, rng) {
+                path.light_hit = None;
+                path.single_wavelength = chromatic_media;
+                rng.start_pixel_sample(first_pixel + i as u32, first_sample + j);
+                rng.skip_dimensions(EYE_RAY_DIMENSIONS);
+                path.direct_light[0] = sample_direct_light(&first_isect, scene, rng);
+                if let Some(next_ray) = first_isect.next_ray(rng) {
                     make_ray_scatter_path(&next_ray, scene, rng, &mut path);
                 }
-                photon_buffer[i] += collect_light_from_path(&mut path);
+                color += if config.spectral {
+                    collect_spectral_light_from_path(&path, eye_ray.wavelength, &to_rgb)
+                } else {
+                    collect_light_from_path(&mut path)
+                };
             }
         }
+        // an eye ray that misses stands for samples_per_first_isect black samples
+        tile.splat(&config.filter, film_pos, color, config.samples_per_first_isect);
+        tile.add_estimate(first_pixel + i as u32, color / config.samples_per_first_isect as f32,
+                          config.samples_per_first_isect);
     }
 }
 
-fn make_eye_rays(camera_position: &Vec3, camera_orientation: &Quaternion,
-                 width: i32, height: i32, y_bounds: (i32, i32), sub_pix: (f32, f32)) -> Vec<Ray> {
+fn make_eye_rays(camera: &Camera, width: i32, height: i32, y_bounds: (i32, i32), sample_index: u32,
+                 spectral: bool, rng: &mut Sampler) -> Vec<(Option<Ray>, (f32, f32))> {
     let fw = width as f32;
     let fh = height as f32;
     let aspect = fw / fh;
-    let fov = 3.14 / 2.0;
-    let top_left_2 = Vec3 {x:-aspect, y:1., z:-1.};
-    let right_step = Vec3 {x:2.*aspect, y:0., z:0.}.smul(1. / (fw-1.));
-    let down_step = Vec3 {x:0., y:-2., z:0.}.smul(1. / (fh-1.));
-    let top_left = top_left_2 + right_step.smul(sub_pix.0) + down_step.smul(sub_pix.1);
+    let dispersive = camera.projection.is_dispersive();
 
     let mut rays = Vec::new();
 
     for y in y_bounds.0..y_bounds.1 {
         for x in 0..width {
-            rays.push(Ray{
-                origin: *camera_position,
-                dir: camera_orientation.vmul(
-                    &(top_left + right_step.smul(x as f32) + down_step.smul(y as f32)).normal()
-                )
+            rng.start_pixel_sample((x + y*width) as u32, sample_index);
+            let sub_pix = rng.next_2d();
+            let time = rng.next_1d();
+            let wavelength = rng.next_1d();
+            let lens = rng.next_2d();
+            let film_pos = (x as f32 + sub_pix.0, y as f32 + sub_pix.1);
+            let wavelength = if spectral { sample_wavelength(wavelength) } else { 0. };
+            let sample = EyeSample { film: (film_pos.0 / fw, film_pos.1 / fh), lens: lens, wavelength: wavelength };
+            let ray = camera.projection.eye_ray(&sample, aspect).map(|(origin, dir)| Ray{
+                origin: camera.position + camera.orientation.vmul(&origin),
+                dir: camera.orientation.vmul(&dir),
+                time: camera.shutter_open + (camera.shutter_close - camera.shutter_open) * time,
+                wavelength: wavelength,
+                // a lens with chromatic aberration bends each wavelength its own way
+                single_wavelength: spectral && dispersive
             });
+            rays.push((ray, film_pos));
         }
     }
     rays
 }
 
 fn path_trace_scene(config: &RenderConfig, camera: &Camera, scene: &Scene, width: i32, height: i32,
-                  y_bounds: (i32, i32), photon_buffer: &mut[Color3f],
-                  rng: &mut rand::ThreadRng) {
-    let subpix = (rng.gen::<f32>(), rng.gen::<f32>());
-    let eye_rays = make_eye_rays(&camera.position, &camera.orientation,
-                                 width, height, y_bounds, subpix);
-
-    assert!(eye_rays.len() == photon_buffer.len());
+                  y_bounds: (i32, i32), iteration: u32, active: Option<&[bool]>, cancel: Option<&CancelToken>,
+                  tile: &mut FilmTile, rng: &mut Sampler) {
+    // each iteration traces samples_per_first_isect samples per pixel
+    let first_sample = iteration * config.samples_per_first_isect;
+    let eye_rays = make_eye_rays(camera, width, height, y_bounds, first_sample, config.spectral, rng);
 
     path_trace_rays(
         config,
         &eye_rays,
-        scene, rng, photon_buffer
+        scene, (y_bounds.0 * width) as u32, first_sample,
+        active, cancel, rng, tile
     );
 }
 
-fn render_pixels<F>(renderer: &mut sdl2::render::Renderer, photon_buffer: &[Color3f],
+/* Map an image the size of the texture into [0..255] with 'color_transform_fn',
+ * writing it straight into the streaming RGB24 texture */
+fn render_pixels<F>(texture: &mut Texture, size: (u32, u32), photon_buffer: &[Color3f],
                     color_transform_fn: F)
     where F: Fn(&Color3f) -> Color3f {
-    
-    let output_size = renderer.output_size().unwrap();
-
-    for y in 0..output_size.0 {
-        for x in 0..output_size.1 {
-            let col = color_transform_fn(&photon_buffer[(x + output_size.0*y) as usize]);
-            renderer.set_draw_color(Color::RGB(
-                col.r as u8,
-                col.g as u8,
-                col.b as u8
-            ));
-            renderer.fill_rect(Rect::new(x as i32, y as i32, 1, 1)).unwrap();
+    texture.with_lock(None, |pixels: &mut [u8], pitch: usize| {
+        for y in 0..size.1 as usize {
+            let row = &photon_buffer[y * size.0 as usize..(y + 1) * size.0 as usize];
+            for (x, c) in row.iter().enumerate() {
+                let col = color_transform_fn(c);
+                let offset = y * pitch + 3 * x;
+                pixels[offset] = col.r as u8;
+                pixels[offset + 1] = col.g as u8;
+                pixels[offset + 2] = col.b as u8;
+            }
         }
-    }
+    }).unwrap();
 }
 
-fn hdr_postprocess_blit(hdr_gamma: f32, renderer: &mut sdl2::render::Renderer, photon_buffer: &Vec<Color3f>) {
-    let max_value = max_value_of_photon_buffer(photon_buffer);
+// size on screen of a pixel of the HUD's font, in window points
+const HUD_SCALE: u32 = 2;
 
-    render_pixels(renderer, photon_buffer, |c: &Color3f| {
-        hdr_log_tonemap(max_value, hdr_gamma, &c)
-    });
+/* Lines of white text on black down the top left of the window, with
+ * 'dpi_scale' drawable pixels to the window point */
+fn draw_hud(renderer: &mut Renderer, dpi_scale: u32, lines: &[String]) {
+    let scale = HUD_SCALE * dpi_scale;
+    let line_height = (viewer::GLYPH_HEIGHT + 2) * scale;
+    for (row, line) in lines.iter().enumerate() {
+        let (x0, y0) = (scale as i32, (row as u32 * line_height + scale) as i32);
+        let (w, h) = viewer::text_size(line);
+        renderer.set_draw_color(Color::RGB(0, 0, 0));
+        renderer.fill_rect(Rect::new(x0 - scale as i32, y0 - scale as i32, (w + 2) * scale, (h + 2) * scale)).unwrap();
+        renderer.set_draw_color(Color::RGB(255, 255, 255));
+        for (x, y) in viewer::text_pixels(line) {
+            renderer.fill_rect(Rect::new(x0 + (x * scale) as i32, y0 + (y * scale) as i32, scale, scale)).unwrap();
+        }
+    }
+}
+
+fn draw_outline(renderer: &mut Renderer, output_size: (u32, u32), outline: &[bool]) {
+    renderer.set_draw_color(Color::RGB(255, 255, 0));
+    for (n, _) in outline.iter().enumerate().filter(|&(_, &edge)| edge) {
+        let (x, y) = (n as u32 % output_size.0, n as u32 / output_size.0);
+        renderer.fill_rect(Rect::new(x as i32, y as i32, 1, 1)).unwrap();
+    }
 }
 
 /* Tone map a color (in a scene containing a maximum colour value of 'max_value') to range [0..255] */
@@ -290,25 +578,54 @@ fn hdr_log_tonemap(max_value: f32, gamma: f32, c: &Color3f) -> Color3f
 }
 
 fn parallel_path_trace_scene(config: &RenderConfig, camera: &Camera, scene: &Scene, width: u32, height: u32,
-                             photon_buffer: &mut[Color3f]) {
-    let chunks: Vec<_> = photon_buffer.chunks_mut((width * height) as usize / config.threads).collect();
-
-    crossbeam::scope(|scope| {
-        for (i, chunk) in chunks.into_iter().enumerate() {
+                             iteration: u32, active: Option<&[bool]>, cancel: Option<&CancelToken>,
+                             film: &mut[FilmPixel]) {
+    // filters splat across the rows of neighbouring threads, so each thread
+    // fills its own tile and they are summed into the film afterwards
+    let tiles: Vec<FilmTile> = crossbeam::scope(|scope| {
+        let handles: Vec<_> = (0..config.threads).map(|i| {
             scope.spawn(move || {
-                let mut rng = rand::thread_rng();
+                let y_bounds = ((i*height as usize / config.threads) as i32,
+                                ((i+1)*height as usize / config.threads) as i32);
+                let mut tile = FilmTile::new(width, height, y_bounds, &config.filter);
+                let mut rng = Sampler::new(config.sampler, rand::thread_rng());
                 path_trace_scene(config,
                                  camera,
                                  scene,
                                  width as i32,
                                  height as i32,
-                                 ((i*height as usize / config.threads) as i32,
-                                  ((i+1)*height as usize / config.threads) as i32),
-                                 chunk,
+                                 y_bounds,
+                                 iteration,
+                                 active,
+                                 cancel,
+                                 &mut tile,
                                  &mut rng);
-            });
-        }
+                tile
+            })
+        }).collect();
+        handles.into_iter().map(|h| h.join()).collect()
     });
+
+    for tile in tiles.iter() {
+        tile.merge_into(film);
+    }
+}
+
+/* Pixels still to be traced this iteration, or None to trace them all */
+fn adaptive_active_pixels(config: &RenderConfig, film: &[FilmPixel], iteration: u32) -> Option<Vec<bool>> {
+    match config.adaptive {
+        Some(ref adaptive) if iteration >= adaptive.min_iterations =>
+            Some(unconverged_pixels(film, adaptive.target_error)),
+        _ => None
+    }
+}
+
+fn adaptive_time_budget_spent(config: &RenderConfig, start_ns: u64) -> bool {
+    match config.adaptive {
+        Some(AdaptiveConfig { time_budget_secs: Some(budget), .. }) =>
+            (time::precise_time_ns() - start_ns) as f32 * 1e-9 > budget,
+        _ => false
+    }
 }
 
 pub fn max_value_of_photon_buffer(photon_buffer: &Vec<Color3f>) -> f32 {
@@ -321,120 +638,425 @@ pub fn max_value_of_photon_buffer(photon_buffer: &Vec<Color3f>) -> f32 {
     max_value
 }
 
-fn normalize_photon_buffer(photon_buffer: &Vec<Color3f>) -> (Vec<Color3f>, f32) {
-    let mut buf = photon_buffer.clone();
-
-    let max_value = max_value_of_photon_buffer(photon_buffer);
+pub fn dump_hdr_postprocessed_image(file_prefix: &str, img_size: (u32, u32), gamma: f32, max_value: f32, photon_buffer: &Vec<Color3f>) {
+    let filename = format!("{}.ppm", file_prefix);
+    println!("Writing RGB tone-mapped image to {}", filename);
 
-    // normalize colour values to [0..1]
-    for c in buf.iter_mut() {
-        *c = Color3f {r: c.r / max_value, g: c.g / max_value, b: c.b / max_value};
+    // write log mapped u8 data (as displayed)
+    let mut rgb: Vec<u8> = Vec::new();
+    for c in photon_buffer.iter() {
+        let tonemapped = hdr_log_tonemap(max_value, gamma, c);
+        rgb.push(tonemapped.r as u8);
+        rgb.push(tonemapped.g as u8);
+        rgb.push(tonemapped.b as u8);
+    }
+    let written = File::create(&filename).and_then(|mut f| {
+        f.write_all(format!("P6 {} {} 255\n", img_size.0, img_size.1).as_bytes())?;
+        f.write_all(&rgb)?;
+        f.sync_data()
+    });
+    if let Err(err) = written {
+        println!("Couldn't write {}: {}", filename, err);
     }
-    (buf, max_value)
 }
 
-pub fn dump_hdr_postprocessed_image(file_prefix: &str, img_size: (u32, u32), gamma: f32, max_value: f32, photon_buffer: &Vec<Color3f>) {
-    //let (buf, max_value) = normalize_photon_buffer(photon_buffer);
+/* Write each face of a rendered cubemap to {file_prefix}_{face}.ppm, all tone mapped alike */
+pub fn dump_cubemap_faces(file_prefix: &str, layout: CubemapLayout, img_size: (u32, u32), gamma: f32, photon_buffer: &Vec<Color3f>) {
+    let max_value = max_value_of_photon_buffer(photon_buffer);
+    let face_size = layout.face_size(img_size);
+    for (name, face) in cubemap_faces(layout, img_size, photon_buffer) {
+        dump_hdr_postprocessed_image(&format!("{}_{}", file_prefix, name), (face_size, face_size), gamma, max_value, &face);
+    }
+}
 
+fn save_photon_buffer(hdr_gamma: f32, stat_samples: u32, img_size: (u32, u32), photon_buffer: &Vec<Color3f>) {
     let t = time::precise_time_ns();
+    let file_prefix = format!("img_{}_{}_samples", t, stat_samples);
+    dump_hdr_postprocessed_image(&file_prefix, img_size, hdr_gamma, max_value_of_photon_buffer(&photon_buffer), &photon_buffer)
+}
 
-    /*
-    {
-        // write raw float data
-        let filename = format!("{}.raw", file_prefix);
-        println!("Writing raw float32 image data to {}", filename);
-        let mut f = match File::create(&filename) {
-            Ok(file) => file,
-            Err(err) => return
-        };
+/* Accumulate 'iterations' passes without opening a preview window. With
+ * adaptive sampling it may stop sooner */
+pub fn render_scene_headless(iterations: i32, config: &RenderConfig, camera: &Camera, scene: &Scene) -> Vec<Color3f>
+{
+    render_scene_with_progress(iterations, config, camera, scene, &CancelToken::new(), |_| {})
+}
 
+/* Headless render reporting its progress to 'observer' after each iteration,
+ * which stops early, with the image so far, once 'cancel' is cancelled */
+pub fn render_scene_with_progress<F>(iterations: i32, config: &RenderConfig, camera: &Camera, scene: &Scene,
+                                     cancel: &CancelToken, mut observer: F) -> Vec<Color3f>
+    where F: FnMut(&Progress)
+{
+    let (width, height) = config.image_size;
+    let mut film = vec![FilmPixel::default(); (width * height) as usize];
+    let mut photon_buffer = Vec::new();
+    let start = time::precise_time_ns();
+    let iterations = iterations.max(0) as u32;
+    let time_budget_secs = config.adaptive.as_ref().and_then(|adaptive| adaptive.time_budget_secs);
 
-        // need to dirty-cast our Vec<Color3f> to [u8]
-        let _c: *const Color3f = &buf[0];
-        let _u: *const u8 = _c as *const _;
-        let us: &[u8] = unsafe {
-            slice::from_raw_parts(_u, buf.len() * mem::size_of::<Color3f>())
+    for i in 0..iterations {
+        if cancel.is_cancelled() {
+            break;
+        }
+        let active = adaptive_active_pixels(config, &film, i);
+        let num_active = match active {
+            Some(ref active) => active.iter().filter(|&&a| a).count() as u32,
+            None => width * height
         };
-        f.write_all(us);
-        f.sync_data();
+        if num_active == 0 {
+            break;
+        }
+        let t = time::precise_time_ns();
+        parallel_path_trace_scene(config, camera, scene, width, height, i,
+                                  active.as_ref().map(|a| &a[..]), Some(cancel), &mut film);
+        let t_ = time::precise_time_ns();
+        resolve_film(&film, &mut photon_buffer);
+
+        let elapsed_secs = (t_ - start) as f32 * 1e-9;
+        observer(&Progress {
+            iteration: i + 1,
+            iterations: iterations,
+            samples_per_pixel: (i + 1) * config.samples_per_first_isect,
+            paths_per_second: (1000000000u64 * (num_active * config.samples_per_first_isect) as u64) / (t_ - t).max(1),
+            elapsed_secs: elapsed_secs,
+            eta_secs: progress::eta_secs(i + 1, iterations, elapsed_secs, time_budget_secs),
+            image: &photon_buffer
+        });
+        if adaptive_time_budget_spent(config, start) {
+            break;
+        }
     }
-    */
-
-    {
-        let filename = format!("{}.ppm", file_prefix);
-        println!("Writing RGB tone-mapped image to {}", filename);
-        let mut f = match File::create(&filename) {
-            Ok(file) => file,
-            Err(err) => return
-        };
+    if photon_buffer.is_empty() {
+        resolve_film(&film, &mut photon_buffer);
+    }
+    photon_buffer
+}
+
+/* While the camera moves the preview is traced at this fraction of the window's resolution */
+const PREVIEW_DOWNSCALE: u32 = 4;
+// relative error shown white in the variance view
+const VARIANCE_VIEW_MAX_ERROR: f32 = 0.1;
 
-        // XXX get proper dimensions!
-        f.write_all(format!("P6 {} {} 255\n", img_size.0, img_size.1).as_bytes());
+/* One pass over a low resolution image, scaled up to 'output_size' */
+fn fast_preview(config: &RenderConfig, camera: &Camera, scene: &Scene, output_size: (u32, u32)) -> Vec<Color3f> {
+    let width = (output_size.0 / PREVIEW_DOWNSCALE).max(1);
+    let height = (output_size.1 / PREVIEW_DOWNSCALE).max(1);
+    let mut film = vec![FilmPixel::default(); (width * height) as usize];
+    let mut small = Vec::new();
+    parallel_path_trace_scene(config, camera, scene, width, height, 0, None, None, &mut film);
+    resolve_film(&film, &mut small);
+    viewer::resample(&small, (width, height), output_size)
+}
 
-        // write log mapped u8 data (as displayed)
-        let mut rgb: Vec<u8> = Vec::new();
-        for c in photon_buffer.iter() {
-            let tonemapped = hdr_log_tonemap(max_value, gamma, c);
-            rgb.push(tonemapped.r as u8);
-            rgb.push(tonemapped.g as u8);
-            rgb.push(tonemapped.b as u8);
+/* The reference image for split screen comparison, and its size */
+fn load_reference_image(filename: &str) -> Option<(Vec<Color3f>, (u32, u32))> {
+    match pnm::read_pnm(filename) {
+        Ok((width, height, pixels)) => Some((pixels, (width as u32, height as u32))),
+        Err(err) => {
+            println!("Couldn't load reference image {}: {}", filename, err);
+            None
         }
-        f.write_all(&rgb);
-        f.sync_data();
     }
 }
 
-fn save_photon_buffer(hdr_gamma: f32, stat_samples: u32, img_size: (u32, u32), photon_buffer: &Vec<Color3f>) {
-    let t = time::precise_time_ns();
-    let file_prefix = format!("img_{}_{}_samples", t, stat_samples);
-    dump_hdr_postprocessed_image(&file_prefix, img_size, hdr_gamma, max_value_of_photon_buffer(&photon_buffer), &photon_buffer)
+/* Drawable pixels to each of the window's points, more than one on HiDPI displays */
+fn window_dpi_scale(renderer: &Renderer, output_size: (u32, u32)) -> u32 {
+    let window_size = renderer.window().map_or(output_size, |window| window.size());
+    (output_size.0 / window_size.0.max(1)).max(1)
+}
+
+/* Streaming texture for images of 'size', and one holding the reference
+ * image scaled to it */
+fn preview_textures(renderer: &Renderer, size: (u32, u32), reference: &Option<(Vec<Color3f>, (u32, u32))>) -> (Texture, Option<Texture>) {
+    let texture = renderer.create_texture_streaming(PixelFormatEnum::RGB24, size.0, size.1).unwrap();
+    let reference_texture = reference.as_ref().map(|&(ref pixels, reference_size)| {
+        let mut texture = renderer.create_texture_streaming(PixelFormatEnum::RGB24, size.0, size.1).unwrap();
+        render_pixels(&mut texture, size, &viewer::resample(pixels, reference_size, size), |c: &Color3f| c.smul(255.));
+        texture
+    });
+    (texture, reference_texture)
 }
 
+/* Render progressively in a preview window, which can fly round the scene,
+ * tune the materials of objects in it, and show other views of it. The
+ * window can be resized, and on HiDPI displays renders at full resolution.
+ * The image returned is scaled to config.image_size, like headless renders */
 pub fn render_scene(iterations: i32, config: &RenderConfig, camera: &Camera, scene: &Scene) -> Vec<Color3f>
 {
-    println!("Keys: <esc> to quit, <s> to dump raw image");
+    println!("Keys: <esc> to quit, <p> to dump raw image, <h> to toggle the HUD");
+    println!("      <1>-<5> for beauty, normal, albedo, depth and variance views, <m> to toggle the samples per pixel map");
+    println!("      <c> to compare with the reference image, <[><]> to move the split");
+    println!("      <w><a><s><d> to fly, <e><q> to rise and fall, <shift> to go faster,");
+    println!("      drag with the left mouse button to look round, scroll to zoom");
+    println!("      right click to pick an object, <tab> to choose a material param, <-><=> to change it");
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem.window("RUSTY BALLS!!", config.image_size.0, config.image_size.1)
         .position_centered()
+        .resizable()
+        .allow_highdpi()
         //.opengl()
         .build()
         .unwrap();
     let mut renderer = window.renderer().build().unwrap();
 
-    let output_size = renderer.output_size().unwrap();
+    // images are the size of the window in drawable pixels, which HiDPI
+    // displays have several of to each of the window's points
+    let mut output_size = renderer.output_size().unwrap();
+    let mut dpi_scale = window_dpi_scale(&renderer, output_size);
+    // materials are tuned on a copy, leaving the caller's scene as it was
+    let mut scene = scene.clone();
     let mut stats_samples_per_pixel: u32 = 0;
     let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut film = vec![FilmPixel::default(); (output_size.0 * output_size.1) as usize];
     let mut photon_buffer = vec![Color3f::default(); (output_size.0 * output_size.1) as usize];
+    let mut start = time::precise_time_ns();
+    let mut fly = FlyCamera::new(camera);
+    let mut last_frame = start;
+    let mut editor = MaterialEditor::new();
+    let mut outline: Option<Vec<bool>> = None;
+    let mut view = View::Beauty;
+    let mut first_hit: Option<Vec<Color3f>> = None;
+    let mut show_hud = true;
+    let mut hud_stats = String::new();
+    let reference = config.reference_image.as_ref().and_then(|filename| load_reference_image(filename));
+    let (mut texture, mut reference_texture) = preview_textures(&renderer, output_size, &reference);
+    let mut compare = false;
+    let mut split = output_size.0 / 2;
 
-    for i in 0..iterations {
+    let mut i = 0;
+    while i < iterations {
+        let mut moved = false;
+        let mut edited = false;
+        let mut resized = false;
+        let last_view = view;
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     std::process::exit(1);
                 },
-                Event::KeyDown { keycode: Some(Keycode::S), .. } => {
+                Event::KeyDown { keycode: Some(Keycode::P), .. } => {
                     save_photon_buffer(config.preview_hdr_gamma, stats_samples_per_pixel, output_size, &photon_buffer);
                 }
+                Event::KeyDown { keycode: Some(Keycode::H), .. } => {
+                    show_hud = !show_hud;
+                }
+                Event::KeyDown { keycode: Some(Keycode::M), .. } => {
+                    view = if view == View::Samples { View::Beauty } else { View::Samples };
+                }
+                Event::KeyDown { keycode: Some(Keycode::Num1), .. } => { view = View::Beauty; }
+                Event::KeyDown { keycode: Some(Keycode::Num2), .. } => { view = View::Normal; }
+                Event::KeyDown { keycode: Some(Keycode::Num3), .. } => { view = View::Albedo; }
+                Event::KeyDown { keycode: Some(Keycode::Num4), .. } => { view = View::Depth; }
+                Event::KeyDown { keycode: Some(Keycode::Num5), .. } => { view = View::Variance; }
+                Event::KeyDown { keycode: Some(Keycode::C), .. } => {
+                    if reference.is_some() {
+                        compare = !compare;
+                    } else {
+                        println!("No reference image to compare with (see RenderConfig reference_image).");
+                    }
+                }
+                Event::KeyDown { keycode: Some(Keycode::LeftBracket), .. } => {
+                    split = split.saturating_sub(output_size.0 / 16);
+                }
+                Event::KeyDown { keycode: Some(Keycode::RightBracket), .. } => {
+                    split = (split + output_size.0 / 16).min(output_size.0);
+                }
+                Event::Window { win_event_id: WindowEventId::SizeChanged, .. } => {
+                    resized = true;
+                }
+                Event::MouseMotion { mousestate, xrel, yrel, .. } if mousestate.left() => {
+                    fly.look(xrel as f32, yrel as f32);
+                    moved = true;
+                }
+                Event::MouseWheel { y, .. } if y != 0 => {
+                    fly.zoom(y as f32);
+                    moved = true;
+                }
+                Event::MouseButtonDown { mouse_btn: Mouse::Right, x, y, .. } => {
+                    // the mouse is in window points
+                    let pixel = ((x.max(0) as u32 * dpi_scale).min(output_size.0 - 1), (y.max(0) as u32 * dpi_scale).min(output_size.1 - 1));
+                    editor.selected = pick_object(&fly.camera, &scene, output_size, pixel);
+                    match editor.selected {
+                        Some(i) => println!("Picked object {}: {}", i, material_editor::describe(&scene.objs[i])),
+                        None => println!("Nothing picked.")
+                    }
+                    outline = None;
+                }
+                Event::KeyDown { keycode: Some(Keycode::Tab), .. } => {
+                    editor.next_param();
+                    println!("Editing {:?}", editor.param);
+                }
+                Event::KeyDown { keycode: Some(key @ Keycode::Minus), .. } |
+                Event::KeyDown { keycode: Some(key @ Keycode::Equals), .. } => {
+                    if let Some(i) = editor.selected {
+                        match scene.objs[i].mat.params {
+                            Some(ref mut params) => {
+                                let steps = if key == Keycode::Equals { 1. } else { -1. };
+                                let value = editor.adjust(params, steps);
+                                println!("{:?} = {}", editor.param, value);
+                                edited = true;
+                            }
+                            None => println!("Object {} can't be edited: its material has no params", i)
+                        }
+                    }
+                }
                 _ => {}
             }
         }
 
-        let t = time::precise_time_ns();
+        // a long pass while a key is held shouldn't leap across the scene
+        let now = time::precise_time_ns();
+        let dt = ((now - last_frame) as f32 * 1e-9).min(0.1);
+        last_frame = now;
+        {
+            let keys = event_pump.keyboard_state();
+            let axis = |more: Scancode, less: Scancode|
+                (keys.is_scancode_pressed(more) as i32 - keys.is_scancode_pressed(less) as i32) as f32;
+            let boost = if keys.is_scancode_pressed(Scancode::LShift) { 4. } else { 1. };
+            let (forward, right, up) = (axis(Scancode::W, Scancode::S), axis(Scancode::D, Scancode::A), axis(Scancode::E, Scancode::Q));
+            if forward != 0. || right != 0. || up != 0. {
+                fly.fly(forward * boost, right * boost, up * boost, dt);
+                moved = true;
+            }
+        }
 
-        parallel_path_trace_scene(config, &camera, &scene, output_size.0, output_size.1, &mut photon_buffer);
-        hdr_postprocess_blit(config.preview_hdr_gamma, &mut renderer, &photon_buffer);
+        if resized {
+            let new_size = renderer.output_size().unwrap();
+            if new_size != output_size && new_size.0 > 0 && new_size.1 > 0 {
+                output_size = new_size;
+                dpi_scale = window_dpi_scale(&renderer, output_size);
+                let textures = preview_textures(&renderer, output_size, &reference);
+                texture = textures.0;
+                reference_texture = textures.1;
+                split = output_size.0 / 2;
+            } else {
+                resized = false;
+            }
+        }
+        if moved || resized {
+            outline = None;
+        }
+        if moved || edited || resized || view != last_view {
+            first_hit = None;
+        }
+        if outline.is_none() {
+            outline = editor.selected.map(|selected| material_editor::outline(&fly.camera, &scene, output_size, selected));
+        }
+        if moved || edited || resized {
+            // everything accumulated so far was of a different scene, or seen from somewhere else
+            film = vec![FilmPixel::default(); (output_size.0 * output_size.1) as usize];
+            photon_buffer = vec![Color3f::default(); (output_size.0 * output_size.1) as usize];
+            stats_samples_per_pixel = 0;
+            start = now;
+            i = 0;
+        }
 
-        let t_ = time::precise_time_ns();
-        stats_samples_per_pixel += config.samples_per_first_isect;
-        println!("{} accumulated samples per pixel. {} ms per frame, {} paths per second.",
-                 stats_samples_per_pixel,
-                 (t_ - t)/1000000,
-                 ((1000000000u64 * (output_size.0 * output_size.1 * (config.samples_per_first_isect)) as u64) / (t_ - t))
-        );
+        let preview = if moved {
+            Some(fast_preview(config, &fly.camera, &scene, output_size))
+        } else {
+            let active = adaptive_active_pixels(config, &film, i as u32);
+            let num_active = match active {
+                Some(ref active) => active.iter().filter(|&&a| a).count() as u32,
+                None => output_size.0 * output_size.1
+            };
+            if num_active == 0 {
+                println!("All pixels converged.");
+                break;
+            }
+
+            let t = time::precise_time_ns();
+
+            parallel_path_trace_scene(config, &fly.camera, &scene, output_size.0, output_size.1, i as u32,
+                                      active.as_ref().map(|a| &a[..]), None, &mut film);
+            resolve_film(&film, &mut photon_buffer);
+
+            let t_ = time::precise_time_ns();
+            stats_samples_per_pixel += config.samples_per_first_isect;
+            let paths_per_second = (1000000000u64 * (num_active * config.samples_per_first_isect) as u64) / (t_ - t).max(1);
+            println!("{} accumulated samples per pixel ({} pixels traced). {} ms per frame, {} paths per second.",
+                     stats_samples_per_pixel,
+                     num_active,
+                     (t_ - t)/1000000,
+                     paths_per_second
+            );
+            hud_stats = format!("{} spp  {} ms/frame  {} paths/s", stats_samples_per_pixel, (t_ - t)/1000000, paths_per_second);
+            None
+        };
+
+        if view.is_first_hit() && first_hit.is_none() {
+            first_hit = Some(first_hit_view(view, &fly.camera, &scene, output_size));
+        }
+        let to_display = |c: &Color3f| c.smul(255.);
+        match (view, &preview, &first_hit) {
+            (_, _, &Some(ref first_hit)) if view.is_first_hit() => render_pixels(&mut texture, output_size, first_hit, to_display),
+            (_, &Some(ref preview), _) => {
+                let max_value = max_value_of_photon_buffer(preview);
+                render_pixels(&mut texture, output_size, preview, |c: &Color3f| hdr_log_tonemap(max_value, config.preview_hdr_gamma, c));
+            }
+            (View::Variance, _, _) => render_pixels(&mut texture, output_size, &relative_error_map(&film, VARIANCE_VIEW_MAX_ERROR), to_display),
+            (View::Samples, _, _) => render_pixels(&mut texture, output_size, &sample_count_map(&film), to_display),
+            _ => {
+                let max_value = max_value_of_photon_buffer(&photon_buffer);
+                render_pixels(&mut texture, output_size, &photon_buffer, |c: &Color3f| hdr_log_tonemap(max_value, config.preview_hdr_gamma, c));
+            }
+        }
+        renderer.copy(&texture, None, None);
+        match reference_texture {
+            Some(ref reference_texture) if compare && split < output_size.0 => {
+                let right = Rect::new(split as i32, 0, output_size.0 - split, output_size.1);
+                renderer.copy(reference_texture, Some(right), Some(right));
+                renderer.set_draw_color(Color::RGB(255, 255, 255));
+                renderer.fill_rect(Rect::new(split as i32, 0, dpi_scale, output_size.1)).unwrap();
+            }
+            _ => {}
+        }
+        if let Some(ref outline) = outline {
+            draw_outline(&mut renderer, output_size, outline);
+        }
+        if show_hud {
+            let mut lines = vec![if moved { "moving".to_string() } else { hud_stats.clone() }];
+            lines.push(format!("view: {}{}", view.name(), if compare { "  left: live  right: reference" } else { "" }));
+            if let Some(selected) = editor.selected {
+                lines.push(match scene.objs[selected].mat.params {
+                    Some(ref params) => format!("object {}  {:?}: {:.2}", selected, editor.param, editor.value(params)),
+                    None => format!("object {}  can't be edited", selected)
+                });
+            }
+            draw_hud(&mut renderer, dpi_scale, &lines);
+        }
         renderer.present();
+
+        if moved {
+            continue;
+        }
+        if adaptive_time_budget_spent(config, start) {
+            println!("Time budget spent.");
+            break;
+        }
+        i += 1;
     }
-    
-    photon_buffer
+
+    viewer::resample(&photon_buffer, output_size, config.image_size)
+}
+
+#[test]
+fn test_moving_primitive() {
+    use vec3::Vec3;
+    use raytracer::Motion;
+    // a ball moving along x crosses a ray down the z axis only at times near 1
+    let ball = Primitive::Moving(Box::new(Primitive::Sphere(Vec3 {x: -2., y: 0., z: -5.}, 0.5)),
+                                 Motion::Linear(Vec3 {x: 2., y: 0., z: 0.}));
+    let ray_at = |time: f32| Ray {origin: Vec3::default(), dir: Vec3 {x: 0., y: 0., z: -1.}, time: time, ..Ray::default()};
+    assert!(primitive_intersects(&ray_at(0.), &ball).is_none());
+    match primitive_intersects(&ray_at(1.), &ball) {
+        Some((IsectFrom::Outside, dist)) => assert!((dist - 4.5).abs() < 1e-4),
+        _ => panic!("moving ball missed at time 1")
+    }
+    // and is hit at its position at the ray's time
+    let hit = Vec3 {x: 0., y: 0., z: -4.5};
+    let normal = ball.normal_at(&hit, &ray_at(1.));
+    assert!((normal - Vec3 {x: 0., y: 0., z: 1.}).length() < 1e-4);
 }
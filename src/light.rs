@@ -0,0 +1,184 @@
+use std::f32;
+use std::f32::consts::PI;
+use vec3::Vec3;
+use color3f::Color3f;
+use raytracer::Ray;
+use sampling;
+
+/* Lights sampled explicitly by the integrator, rather than found by paths
+ * happening to hit emissive geometry. Point and spot lights give 'intensity'
+ * (power per unit solid angle), directional lights the 'irradiance' they cast
+ * on a surface facing them, and area lights the radiance leaving their surface.
+ * Lights with 'visible' set are also seen by eye rays and mirror reflections */
+#[derive(Clone, Debug)]
+pub enum Light {
+    Point { position: Vec3, intensity: Color3f },
+    // full intensity within cos_inner of 'direction', falling off smoothly to nothing at cos_outer
+    Spot { position: Vec3, direction: Vec3, intensity: Color3f, cos_inner: f32, cos_outer: f32 },
+    // 'direction' points towards the light. a zero angular diameter gives hard shadows
+    Directional { direction: Vec3, irradiance: Color3f, angular_diameter: f32, visible: bool },
+    // emits from the side edge_u x edge_v faces
+    Quad { corner: Vec3, edge_u: Vec3, edge_v: Vec3, radiance: Color3f, visible: bool },
+    Sphere { center: Vec3, radius: f32, radiance: Color3f, visible: bool }
+}
+
+/* Incoming light at a point: unit direction towards the light, distance to it
+ * (infinite for directional lights), and incident radiance divided by the
+ * probability density of having picked that direction */
+#[derive(Clone, Copy, Debug)]
+pub struct LightSample {
+    pub dir: Vec3,
+    pub dist: f32,
+    pub contribution: Color3f
+}
+
+impl Light {
+    pub fn sample(&self, pos: &Vec3, u: (f32, f32)) -> Option<LightSample> {
+        match *self {
+            Light::Point { position, intensity } => {
+                let to_light = position - *pos;
+                let dist = to_light.length();
+                Some(LightSample { dir: to_light.smul(1. / dist), dist: dist, contribution: intensity.smul(1. / (dist*dist)) })
+            }
+            Light::Spot { position, direction, intensity, cos_inner, cos_outer } => {
+                let to_light = position - *pos;
+                let dist = to_light.length();
+                let dir = to_light.smul(1. / dist);
+                let falloff = smoothstep(cos_outer, cos_inner, (-dir).dot(&direction.normal()));
+                if falloff > 0. {
+                    Some(LightSample { dir: dir, dist: dist, contribution: intensity.smul(falloff / (dist*dist)) })
+                } else {
+                    None
+                }
+            }
+            Light::Directional { direction, irradiance, angular_diameter, .. } => {
+                let axis = direction.normal();
+                let dir = if angular_diameter > 0. {
+                    sampling::uniform_cone(&axis, (0.5 * angular_diameter).cos(), u).0
+                } else {
+                    axis
+                };
+                // radiance over the disc is irradiance / solid angle, which the pdf cancels
+                Some(LightSample { dir: dir, dist: f32::INFINITY, contribution: irradiance })
+            }
+            Light::Quad { corner, edge_u, edge_v, radiance, .. } => {
+                let point = corner + edge_u.smul(u.0) + edge_v.smul(u.1);
+                let normal = edge_u.cross(&edge_v);
+                let area = normal.length();
+                let to_light = point - *pos;
+                let dist = to_light.length();
+                let dir = to_light.smul(1. / dist);
+                let cos_light = -dir.dot(&normal) / area;
+                if cos_light > 0. {
+                    Some(LightSample { dir: dir, dist: dist, contribution: radiance.smul(cos_light * area / (dist*dist)) })
+                } else {
+                    None
+                }
+            }
+            Light::Sphere { center, radius, radiance, .. } => {
+                let to_center = center - *pos;
+                let dist_sq = to_center.dot(&to_center);
+                if dist_sq <= radius*radius {
+                    return None;
+                }
+                // sample the cone of directions the sphere subtends
+                let cos_max = (1. - radius*radius / dist_sq).max(0.).sqrt();
+                let (dir, pdf) = sampling::uniform_cone(&to_center.normal(), cos_max, u);
+                let dist = match sphere_distance(pos, &dir, &center, radius) {
+                    Some(dist) => dist,
+                    // grazing the silhouette
+                    None => dist_sq.sqrt()
+                };
+                Some(LightSample { dir: dir, dist: dist, contribution: radiance.smul(1. / pdf) })
+            }
+        }
+    }
+
+    /* Distance along 'ray' to a visible light and the radiance seen there */
+    pub fn intersect(&self, ray: &Ray) -> Option<(f32, Color3f)> {
+        match *self {
+            Light::Directional { direction, irradiance, angular_diameter, visible: true } if angular_diameter > 0. => {
+                let cos_max = (0.5 * angular_diameter).cos();
+                if ray.dir.dot(&direction.normal()) >= cos_max {
+                    Some((f32::INFINITY, irradiance.smul(1. / (2. * PI * (1. - cos_max)))))
+                } else {
+                    None
+                }
+            }
+            Light::Quad { corner, edge_u, edge_v, radiance, visible: true } => {
+                let normal = edge_u.cross(&edge_v);
+                let denom = ray.dir.dot(&normal);
+                // one sided, like the light it casts
+                if denom >= 0. {
+                    return None;
+                }
+                let dist = (corner - ray.origin).dot(&normal) / denom;
+                if dist <= 0. {
+                    return None;
+                }
+                let offset = ray.origin + ray.dir.smul(dist) - corner;
+                let u = offset.dot(&edge_u) / edge_u.dot(&edge_u);
+                let v = offset.dot(&edge_v) / edge_v.dot(&edge_v);
+                if u >= 0. && u <= 1. && v >= 0. && v <= 1. {
+                    Some((dist, radiance))
+                } else {
+                    None
+                }
+            }
+            Light::Sphere { center, radius, radiance, visible: true } => {
+                match sphere_distance(&ray.origin, &ray.dir, &center, radius) {
+                    Some(dist) => Some((dist, radiance)),
+                    None => None
+                }
+            }
+            _ => None
+        }
+    }
+}
+
+fn sphere_distance(origin: &Vec3, dir: &Vec3, center: &Vec3, radius: f32) -> Option<f32> {
+    let v = *origin - *center;
+    let b = -v.dot(dir);
+    let sq_det = b*b - v.dot(&v) + radius*radius;
+    if sq_det < 0. {
+        return None;
+    }
+    let dist = b - sq_det.sqrt();
+    if dist > 0. { Some(dist) } else { None }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge1 <= edge0 {
+        return if x >= edge1 { 1. } else { 0. };
+    }
+    let t = ((x - edge0) / (edge1 - edge0)).max(0.).min(1.);
+    t * t * (3. - 2. * t)
+}
+
+#[test]
+fn test_light_sample() {
+    let white = Color3f {r: 1., g: 1., b: 1.};
+    let origin = Vec3 {x: 0., y: 0., z: 0.};
+    let point = Light::Point { position: Vec3 {x: 0., y: 2., z: 0.}, intensity: white.smul(4.) };
+    let s = point.sample(&origin, (0.5, 0.5)).unwrap();
+    assert_eq!(s.dir, Vec3 {x: 0., y: 1., z: 0.});
+    assert_eq!((s.dist, s.contribution.r), (2., 1.));
+    let spot = Light::Spot { position: Vec3 {x: 0., y: 2., z: 0.}, direction: Vec3 {x: 0., y: -1., z: 0.},
+                             intensity: white, cos_inner: 0.95, cos_outer: 0.9 };
+    assert!(spot.sample(&origin, (0.5, 0.5)).is_some());
+    assert!(spot.sample(&Vec3 {x: 2., y: 0., z: 0.}, (0.5, 0.5)).is_none());
+    // a small quad far away approaches a point light of intensity radiance * area
+    let quad = Light::Quad { corner: Vec3 {x: -0.05, y: 10., z: -0.05}, edge_u: Vec3 {x: 0.1, y: 0., z: 0.},
+                             edge_v: Vec3 {x: 0., y: 0., z: 0.1}, radiance: white.smul(100.), visible: true };
+    let s = quad.sample(&origin, (0.5, 0.5)).unwrap();
+    assert!((s.contribution.r - 0.01).abs() < 1e-4);
+    assert!(quad.sample(&Vec3 {x: 0., y: 20., z: 0.}, (0.5, 0.5)).is_none());
+    let up = Ray {origin: origin, dir: Vec3 {x: 0., y: 1., z: 0.}, ..Ray::default()};
+    assert_eq!(quad.intersect(&up).map(|(d, _)| d), Some(10.));
+    // the sampled cone of a sphere light integrates to its radiance times its solid angle
+    let sphere = Light::Sphere { center: Vec3 {x: 0., y: 5., z: 0.}, radius: 1., radiance: white, visible: false };
+    let s = sphere.sample(&origin, (0.3, 0.7)).unwrap();
+    assert!((s.contribution.r - 2. * PI * (1. - (24f32 / 25.).sqrt())).abs() < 1e-4);
+    assert!(s.dist > 3.9 && s.dist < 5.);
+    assert!(sphere.intersect(&up).is_none());
+}
@@ -0,0 +1,127 @@
+use raytracer::{MaterialParams,SceneObj,Primitive,Camera,Scene};
+use pick_object;
+
+/* Tuning of an object's material params from the preview window: one object
+ * is picked at a time, and one of its params stepped up or down */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MaterialParam {
+    Red,
+    Green,
+    Blue,
+    Roughness,
+    Ior
+}
+
+const PARAMS: [MaterialParam; 5] = [MaterialParam::Red, MaterialParam::Green, MaterialParam::Blue,
+                                    MaterialParam::Roughness, MaterialParam::Ior];
+// change of a param per step
+const STEP: f32 = 0.05;
+
+pub struct MaterialEditor {
+    // index into scene.objs
+    pub selected: Option<usize>,
+    pub param: MaterialParam
+}
+
+impl MaterialEditor {
+    pub fn new() -> MaterialEditor {
+        MaterialEditor { selected: None, param: MaterialParam::Red }
+    }
+
+    pub fn next_param(&mut self) {
+        let i = PARAMS.iter().position(|&p| p == self.param).unwrap();
+        self.param = PARAMS[(i + 1) % PARAMS.len()];
+    }
+
+    pub fn value(&self, params: &MaterialParams) -> f32 {
+        match self.param {
+            MaterialParam::Red => params.color.r,
+            MaterialParam::Green => params.color.g,
+            MaterialParam::Blue => params.color.b,
+            MaterialParam::Roughness => params.roughness,
+            MaterialParam::Ior => params.ior
+        }
+    }
+
+    /* Step the current param by 'steps', keeping it in its valid range, and
+     * return its new value */
+    pub fn adjust(&self, params: &mut MaterialParams, steps: f32) -> f32 {
+        let value = self.value(params) + steps * STEP;
+        let (min, max) = if self.param == MaterialParam::Ior { (1., 4.) } else { (0., 1.) };
+        let value = value.max(min).min(max);
+        match self.param {
+            MaterialParam::Red => params.color.r = value,
+            MaterialParam::Green => params.color.g = value,
+            MaterialParam::Blue => params.color.b = value,
+            MaterialParam::Roughness => params.roughness = value,
+            MaterialParam::Ior => params.ior = value
+        }
+        value
+    }
+}
+
+pub fn describe_primitive(prim: &Primitive) -> String {
+    match *prim {
+        Primitive::Sphere(center, radius) => format!("sphere at {:?}, radius {}", center, radius),
+        Primitive::Triangle(v1, v2, v3) => format!("triangle {:?} {:?} {:?}", v1, v2, v3),
+        Primitive::Plane(origin, normal) => format!("plane through {:?}, normal {:?}", origin, normal),
+        Primitive::Heightfield(ref hf) => format!("{}x{} heightfield at {:?}, size {:?}", hf.width, hf.depth, hf.origin, hf.size),
+        Primitive::Moving(ref prim, _) => format!("moving {}", describe_primitive(prim)),
+        Primitive::ScatterEvent => "scatter event".to_string()
+    }
+}
+
+/* The object's primitive and material, and whether the material can be
+ * tuned: only materials with params, read by their programs, can be */
+pub fn describe(obj: &SceneObj) -> String {
+    let params = match obj.mat.params {
+        Some(ref params) => format!("{:?}", params),
+        None => "no params, can't be edited".to_string()
+    };
+    format!("{}\n  material: {}{}", describe_primitive(&obj.prim), params,
+            if obj.mat.light_program.is_some() { ", samples lights" } else { "" })
+}
+
+/* Pixels on the edge of the object 'selected' in an image of 'image_size',
+ * for highlighting it */
+pub fn outline(camera: &Camera, scene: &Scene, image_size: (u32, u32), selected: usize) -> Vec<bool> {
+    let (width, height) = image_size;
+    let covered: Vec<bool> = (0..width * height)
+        .map(|n| pick_object(camera, scene, image_size, (n % width, n / width)) == Some(selected))
+        .collect();
+    let at = |x: i64, y: i64| x >= 0 && y >= 0 && x < width as i64 && y < height as i64 && covered[(x + y * width as i64) as usize];
+    (0..width * height).map(|n| {
+        let (x, y) = ((n % width) as i64, (n / width) as i64);
+        at(x, y) && !(at(x - 1, y) && at(x + 1, y) && at(x, y - 1) && at(x, y + 1))
+    }).collect()
+}
+
+#[test]
+fn test_material_editor() {
+    use vec3::Vec3;
+    use raytracer::Material;
+    let mut editor = MaterialEditor::new();
+    let mut params = MaterialParams::default();
+    assert_eq!(editor.adjust(&mut params, 4.), 1.);
+    editor.next_param();
+    assert_eq!(editor.param, MaterialParam::Green);
+    for _ in 0..3 {
+        editor.next_param();
+    }
+    assert_eq!(editor.param, MaterialParam::Ior);
+    assert!((editor.adjust(&mut params, -2.) - 1.4).abs() < 1e-5);
+    assert_eq!(editor.adjust(&mut params, -100.), 1.);
+    editor.next_param();
+    assert_eq!(editor.param, MaterialParam::Red);
+
+    // a ball in front of the camera is picked in the middle of the image, and outlined round its edge
+    let ball = SceneObj { prim: Primitive::Sphere(Vec3 {x: 0., y: 0., z: -3.}, 1.), mat: Material::default(), medium: None };
+    let scene = Scene { objs: vec![SceneObj::default(), ball], lights: Vec::new() };
+    let camera = Camera::default();
+    assert_eq!(pick_object(&camera, &scene, (32, 32), (16, 16)), Some(1));
+    assert_eq!(pick_object(&camera, &scene, (32, 32), (0, 0)), None);
+    let edge = outline(&camera, &scene, (32, 32), 1);
+    assert!(!edge[16 + 16 * 32] && edge.iter().any(|&e| e));
+    assert!(describe(&scene.objs[1]).starts_with("sphere"));
+    assert!(describe(&scene.objs[1]).contains("can't be edited"));
+}
@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use color3f::Color3f;
+
+/* Width, height and pixels, row by row from the top with channels in [0..1],
+ * of a binary greyscale (P5) or RGB (P6) PNM image */
+pub fn read_pnm(filename: &str) -> io::Result<(usize, usize, Vec<Color3f>)> {
+    let mut data = Vec::new();
+    File::open(filename)?.read_to_end(&mut data)?;
+
+    let bad_file = || io::Error::new(io::ErrorKind::InvalidData, "not a binary PGM/PPM file");
+
+    // header: magic, width, height, maxval, then one whitespace byte
+    let mut header: Vec<String> = Vec::new();
+    let mut pos = 0;
+    while header.len() < 4 {
+        while pos < data.len() && (data[pos] as char).is_whitespace() { pos += 1; }
+        if pos < data.len() && data[pos] == b'#' {
+            while pos < data.len() && data[pos] != b'\n' { pos += 1; }
+            continue;
+        }
+        let start = pos;
+        while pos < data.len() && !(data[pos] as char).is_whitespace() { pos += 1; }
+        if start == pos { return Err(bad_file()); }
+        header.push(String::from_utf8_lossy(&data[start..pos]).into_owned());
+    }
+    pos += 1;
+
+    let channels = match header[0].as_str() {
+        "P5" => 1,
+        "P6" => 3,
+        _ => return Err(bad_file())
+    };
+    let width: usize = header[1].parse().map_err(|_| bad_file())?;
+    let height: usize = header[2].parse().map_err(|_| bad_file())?;
+    let maxval: f32 = header[3].parse().map_err(|_| bad_file())?;
+    if maxval > 255. || data.len() < pos + width*height*channels {
+        return Err(bad_file());
+    }
+
+    let pixels = &data[pos..];
+    let channel = |i: usize| pixels[i] as f32 / maxval;
+    let colors = (0..width*height).map(|i| {
+        if channels == 1 {
+            Color3f {r: channel(i), g: channel(i), b: channel(i)}
+        } else {
+            Color3f {r: channel(3*i), g: channel(3*i + 1), b: channel(3*i + 2)}
+        }
+    }).collect();
+    Ok((width, height, colors))
+}
+
+/* Mean of the channels, as greyscale images store */
+pub fn brightness(c: &Color3f) -> f32 {
+    (c.r + c.g + c.b) / 3.
+}
+
+#[test]
+fn test_read_pnm() {
+    use std::{env,fs,process};
+    // named for the process, so concurrent test runs don't share files
+    let write = |name: &str, data: &[u8]| {
+        let path = env::temp_dir().join(format!("rustyballs_{}_{}", process::id(), name));
+        File::create(&path).unwrap().write_all(data).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let ppm = write("test.ppm", b"P6\n# comment\n2 1 255\n\xff\x00\x00\x00\x33\xff");
+    let (width, height, pixels) = read_pnm(&ppm).unwrap();
+    assert_eq!((width, height), (2, 1));
+    assert!(pixels[0].r == 1. && pixels[0].g == 0. && (pixels[1].g - 0.2).abs() < 1e-6 && pixels[1].b == 1.);
+    let pgm = write("test.pgm", b"P5 1 2 100 \x32\x64");
+    let (_, _, pixels) = read_pnm(&pgm).unwrap();
+    assert!(pixels[0].r == 0.5 && pixels[0].b == 0.5 && brightness(&pixels[1]) == 1.);
+    let short = write("test_short.ppm", b"P6 4 4 255\n\x00");
+    assert!(read_pnm(&short).is_err());
+    for path in [ppm, pgm, short].iter() {
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering};
+use color3f::Color3f;
+
+/* Stops a render from another thread: clones share the flag, and the render
+ * and its worker threads give up soon after any of them is cancelled */
+#[derive(Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+/* Where a render has got to, passed to its observer after each iteration */
+pub struct Progress<'a> {
+    // iterations done, of at most 'iterations'
+    pub iteration: u32,
+    pub iterations: u32,
+    // accumulated samples per pixel, of those traced every iteration
+    pub samples_per_pixel: u32,
+    pub paths_per_second: u64,
+    pub elapsed_secs: f32,
+    // estimated time left, or None before there is anything to go by
+    pub eta_secs: Option<f32>,
+    // the image so far
+    pub image: &'a [Color3f]
+}
+
+impl<'a> Progress<'a> {
+    pub fn fraction_done(&self) -> f32 {
+        if self.iterations == 0 { 1. } else { self.iteration as f32 / self.iterations as f32 }
+    }
+}
+
+/* Time left at the rate so far, no more than what is left of any time budget */
+pub fn eta_secs(iteration: u32, iterations: u32, elapsed_secs: f32, time_budget_secs: Option<f32>) -> Option<f32> {
+    if iteration == 0 {
+        return None;
+    }
+    let eta = elapsed_secs / iteration as f32 * iterations.saturating_sub(iteration) as f32;
+    Some(match time_budget_secs {
+        Some(budget) => eta.min((budget - elapsed_secs).max(0.)),
+        None => eta
+    })
+}
+
+#[test]
+fn test_progress() {
+    let token = CancelToken::new();
+    let worker = token.clone();
+    assert!(!worker.is_cancelled());
+    token.cancel();
+    assert!(worker.is_cancelled());
+
+    assert_eq!(eta_secs(0, 10, 0., None), None);
+    assert_eq!(eta_secs(2, 10, 4., None), Some(16.));
+    assert_eq!(eta_secs(2, 10, 4., Some(10.)), Some(6.));
+    assert_eq!(eta_secs(10, 10, 20., None), Some(0.));
+    let progress = Progress { iteration: 5, iterations: 20, samples_per_pixel: 20, paths_per_second: 0,
+                              elapsed_secs: 1., eta_secs: Some(3.), image: &[] };
+    assert_eq!(progress.fraction_done(), 0.25);
+}
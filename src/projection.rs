@@ -0,0 +1,273 @@
+use std::f32;
+use std::f32::consts::PI;
+use std::sync::Arc;
+use vec3::Vec3;
+use color3f::Color3f;
+use lens::{LensSystem,ThinLens};
+
+/* Sample values an eye ray is generated from: its position on the film, in
+ * [0..1) across and down the image, a point on the lens in [0..1)^2 for
+ * projections that model one, and the ray's wavelength in nm, 0 when not
+ * rendering spectrally */
+#[derive(Clone, Copy, Debug)]
+pub struct EyeSample {
+    pub film: (f32, f32),
+    pub lens: (f32, f32),
+    pub wavelength: f32
+}
+
+/* Generator of eye rays for positions on the film. Implemented by closures
+ * taking the same arguments, for one-off projections such as lens distortion
+ * models or scientific viewpoints */
+pub trait RayGenerator: Send + Sync {
+    /* Origin and direction of the ray through 'film', in [0..1) across and
+     * down an image of width / height 'aspect', or None where the projection
+     * leaves the film blank. Rays are in the camera's frame, starting near its
+     * origin and looking down -z with +y up */
+    fn ray(&self, film: (f32, f32), aspect: f32) -> Option<(Vec3, Vec3)>;
+}
+
+impl<F> RayGenerator for F where F: Fn((f32, f32), f32) -> Option<(Vec3, Vec3)> + Send + Sync {
+    fn ray(&self, film: (f32, f32), aspect: f32) -> Option<(Vec3, Vec3)> {
+        self(film, aspect)
+    }
+}
+
+/* How a camera maps positions on the film to eye rays */
+#[derive(Clone)]
+pub enum Projection {
+    // pinhole, with vertical field of view 'fov' radians. 'shift' moves the
+    // lens off axis, in units of half the film height
+    Perspective { fov: f32, shift: (f32, f32) },
+    // parallel rays from a film 'height' units tall, for technical illustration
+    Orthographic { height: f32 },
+    // all six faces of a cube round the camera, each a 90 degree perspective view
+    Cubemap(CubemapLayout),
+    // longitude across and latitude down a 2:1 image, covering the whole sphere
+    Equirectangular,
+    // equidistant fisheye seeing 'fov' radians across the circle inscribed in the image
+    Fisheye { fov: f32 },
+    // omni-directional stereo (Peleg et al. 2001): an equirectangular panorama
+    // for each eye, left above right, with eyes 'ipd' apart looking out from
+    // opposite sides of a circle
+    OmniStereo { ipd: f32 },
+    // perspective with depth of field, bokeh and lens aberrations
+    ThinLens(ThinLens),
+    // eye rays traced through a multi-element lens onto the scene
+    LensSystem(LensSystem),
+    Custom(Arc<dyn RayGenerator>)
+}
+
+impl Default for Projection {
+    fn default() -> Projection {
+        Projection::perspective(0.5 * PI)
+    }
+}
+
+/* Arrangement of cubemap faces in one image, each face square */
+#[derive(Clone, Copy, Debug)]
+pub enum CubemapLayout {
+    // six faces side by side, in the order of CUBE_FACES
+    Strip,
+    // a horizontal cross of left, front, right and back, with up above and
+    // down below the front
+    Cross
+}
+
+/* A cube face: the suffix it's written with, its forward and up directions */
+pub struct CubeFace {
+    pub name: &'static str,
+    pub forward: Vec3,
+    pub up: Vec3
+}
+
+pub const CUBE_FACES: [CubeFace; 6] = [
+    CubeFace { name: "fr", forward: Vec3 {x: 0., y: 0., z: -1.}, up: Vec3 {x: 0., y: 1., z: 0.} },
+    CubeFace { name: "bk", forward: Vec3 {x: 0., y: 0., z: 1.}, up: Vec3 {x: 0., y: 1., z: 0.} },
+    CubeFace { name: "up", forward: Vec3 {x: 0., y: 1., z: 0.}, up: Vec3 {x: 0., y: 0., z: 1.} },
+    CubeFace { name: "dn", forward: Vec3 {x: 0., y: -1., z: 0.}, up: Vec3 {x: 0., y: 0., z: -1.} },
+    CubeFace { name: "lf", forward: Vec3 {x: -1., y: 0., z: 0.}, up: Vec3 {x: 0., y: 1., z: 0.} },
+    CubeFace { name: "rt", forward: Vec3 {x: 1., y: 0., z: 0.}, up: Vec3 {x: 0., y: 1., z: 0.} }
+];
+
+impl CubemapLayout {
+    /* Size of an image laying out faces 'face_size' pixels square */
+    pub fn image_size(&self, face_size: u32) -> (u32, u32) {
+        match *self {
+            CubemapLayout::Strip => (6 * face_size, face_size),
+            CubemapLayout::Cross => (4 * face_size, 3 * face_size)
+        }
+    }
+
+    pub fn face_size(&self, image_size: (u32, u32)) -> u32 {
+        match *self {
+            CubemapLayout::Strip => image_size.1,
+            CubemapLayout::Cross => image_size.0 / 4
+        }
+    }
+
+    /* (column, row) of each of CUBE_FACES, in units of faces */
+    fn face_cells(&self) -> [(u32, u32); 6] {
+        match *self {
+            CubemapLayout::Strip => [(0, 0), (1, 0), (2, 0), (3, 0), (4, 0), (5, 0)],
+            CubemapLayout::Cross => [(1, 1), (3, 1), (1, 0), (1, 2), (0, 1), (2, 1)]
+        }
+    }
+}
+
+fn equirectangular_direction(u: f32, v: f32) -> Vec3 {
+    let longitude = (2. * u - 1.) * PI;
+    let latitude = (0.5 - v) * PI;
+    Vec3 {x: longitude.sin() * latitude.cos(), y: latitude.sin(), z: -longitude.cos() * latitude.cos()}
+}
+
+impl Projection {
+    pub fn perspective(fov: f32) -> Projection {
+        Projection::Perspective { fov: fov, shift: (0., 0.) }
+    }
+
+    /* The eye ray for 'sample': as ray(), but through the sampled point on
+     * the lens, at the sampled wavelength, for projections that model one */
+    pub fn eye_ray(&self, sample: &EyeSample, aspect: f32) -> Option<(Vec3, Vec3)> {
+        match *self {
+            Projection::ThinLens(ref lens) => lens.ray(sample, aspect),
+            Projection::LensSystem(ref lens) => lens.ray(sample, aspect),
+            _ => self.ray(sample.film, aspect)
+        }
+    }
+
+    /* Whether eye rays depend on the sample's wavelength, so the paths they
+     * start can only carry that one */
+    pub fn is_dispersive(&self) -> bool {
+        match *self {
+            Projection::ThinLens(ref lens) => lens.is_dispersive(),
+            _ => false
+        }
+    }
+}
+
+impl RayGenerator for Projection {
+    fn ray(&self, film: (f32, f32), aspect: f32) -> Option<(Vec3, Vec3)> {
+        let (u, v) = film;
+        let origin = Vec3 {x: 0., y: 0., z: 0.};
+        match *self {
+            Projection::Perspective { fov, shift } => {
+                let half_height = (0.5 * fov).tan();
+                Some((origin, Vec3 {x: ((2. * u - 1.) * aspect + shift.0) * half_height,
+                                    y: (1. - 2. * v + shift.1) * half_height, z: -1.}.normal()))
+            }
+            Projection::Orthographic { height } => {
+                let film_pos = Vec3 {x: (2. * u - 1.) * aspect * 0.5 * height, y: (1. - 2. * v) * 0.5 * height, z: 0.};
+                Some((film_pos, Vec3 {x: 0., y: 0., z: -1.}))
+            }
+            Projection::Cubemap(layout) => {
+                let (columns, rows) = layout.image_size(1);
+                let (cell_u, cell_v) = (u * columns as f32, v * rows as f32);
+                let cell = (cell_u.floor() as u32, cell_v.floor() as u32);
+                layout.face_cells().iter().position(|&c| c == cell).map(|i| {
+                    let face = &CUBE_FACES[i];
+                    let right = face.forward.cross(&face.up);
+                    let (s, t) = (cell_u - cell.0 as f32, cell_v - cell.1 as f32);
+                    (origin, (face.forward + right.smul(2. * s - 1.) + face.up.smul(1. - 2. * t)).normal())
+                })
+            }
+            Projection::Equirectangular => Some((origin, equirectangular_direction(u, v))),
+            Projection::Fisheye { fov } => {
+                // the inscribed circle spans the shorter side of the image
+                let scale = aspect.min(1.);
+                let (x, y) = ((2. * u - 1.) * aspect / scale, (1. - 2. * v) / scale);
+                let r = (x*x + y*y).sqrt();
+                if r > 1. {
+                    return None;
+                }
+                let theta = r * 0.5 * fov;
+                let (sx, sy) = if r > 0. { (x / r, y / r) } else { (0., 0.) };
+                Some((origin, Vec3 {x: theta.sin() * sx, y: theta.sin() * sy, z: -theta.cos()}))
+            }
+            Projection::OmniStereo { ipd } => {
+                let (eye, v) = if v < 0.5 { (-1., 2. * v) } else { (1., 2. * v - 1.) };
+                let dir = equirectangular_direction(u, v);
+                // each eye sits to its side of the horizontal view direction.
+                // the separation closes up towards the poles, where the eyes'
+                // circle would otherwise twist the stereo round
+                let longitude = (2. * u - 1.) * PI;
+                let right = Vec3 {x: longitude.cos(), y: 0., z: longitude.sin()};
+                let latitude = (0.5 - v) * PI;
+                Some((right.smul(eye * 0.5 * ipd * latitude.cos()), dir))
+            }
+            // through the middle of the lens
+            Projection::ThinLens(ref lens) => lens.ray(&EyeSample { film: film, lens: (0.5, 0.5), wavelength: 0. }, aspect),
+            Projection::LensSystem(ref lens) => lens.ray(&EyeSample { film: film, lens: (0.5, 0.5), wavelength: 0. }, aspect),
+            Projection::Custom(ref generator) => generator.ray(film, aspect)
+        }
+    }
+}
+
+/* Cut the faces out of a rendered cubemap, each paired with the name of its face */
+pub fn cubemap_faces(layout: CubemapLayout, image_size: (u32, u32), photon_buffer: &Vec<Color3f>) -> Vec<(&'static str, Vec<Color3f>)> {
+    let n = layout.face_size(image_size);
+    layout.face_cells().iter().zip(CUBE_FACES.iter()).map(|(&(column, row), face)| {
+        let mut pixels = Vec::with_capacity((n * n) as usize);
+        for y in row*n..(row + 1)*n {
+            let start = (y * image_size.0 + column * n) as usize;
+            pixels.extend_from_slice(&photon_buffer[start..start + n as usize]);
+        }
+        (face.name, pixels)
+    }).collect()
+}
+
+#[test]
+fn test_projections() {
+    let close = |a: Vec3, b: Vec3| (a - b).length() < 1e-4;
+    let forward = Vec3 {x: 0., y: 0., z: -1.};
+    let dir = |projection: Projection, film: (f32, f32), aspect: f32| projection.ray(film, aspect).map(|(_, dir)| dir);
+    for projection in vec![Projection::default(), Projection::Equirectangular, Projection::Fisheye { fov: PI }] {
+        assert!(close(dir(projection, (0.5, 0.5), 2.).unwrap(), forward));
+    }
+    // 90 degrees vertically
+    let top = dir(Projection::default(), (0.5, 0.), 1.).unwrap();
+    assert!((top.dot(&forward) - (0.25 * PI).cos()).abs() < 1e-4);
+    assert!(close(dir(Projection::Equirectangular, (0.75, 0.5), 2.).unwrap(), Vec3 {x: 1., y: 0., z: 0.}));
+    assert!(close(dir(Projection::Equirectangular, (0.3, 0.), 2.).unwrap(), Vec3 {x: 0., y: 1., z: 0.}));
+    let fisheye = Projection::Fisheye { fov: PI };
+    assert!(close(dir(fisheye.clone(), (1., 0.5), 1.).unwrap(), Vec3 {x: 1., y: 0., z: 0.}));
+    assert!(dir(fisheye.clone(), (0.99, 0.99), 1.).is_none());
+    // on a wide image the circle touches the top and bottom, leaving the sides blank
+    assert!(close(dir(fisheye.clone(), (0.75, 0.5), 2.).unwrap(), Vec3 {x: 1., y: 0., z: 0.}));
+    assert!(close(dir(fisheye.clone(), (0.5, 0.), 2.).unwrap(), Vec3 {x: 0., y: 1., z: 0.}));
+    assert!(dir(fisheye.clone(), (0.9, 0.5), 2.).is_none());
+    // and on a tall one it touches the sides
+    assert!(close(dir(fisheye.clone(), (0.5, 0.25), 0.5).unwrap(), Vec3 {x: 0., y: 1., z: 0.}));
+    assert!(dir(fisheye, (0.5, 0.1), 0.5).is_none());
+    let (corner, corner_dir) = Projection::Orthographic { height: 2. }.ray((0., 1.), 1.5).unwrap();
+    assert!(close(corner, Vec3 {x: -1.5, y: -1., z: 0.}) && close(corner_dir, forward));
+    // user supplied generators
+    let squash = 0.5;
+    let custom = Projection::Custom(Arc::new(move |film: (f32, f32), _: f32| {
+        Some((Vec3 {x: 0., y: 0., z: 0.}, Vec3 {x: (film.0 - 0.5) * squash, y: 0., z: -1.}.normal()))
+    }));
+    assert!(close(dir(custom, (0.5, 0.9), 1.).unwrap(), forward));
+    // every face's centre looks along it, and the cross leaves its corners blank
+    for &layout in [CubemapLayout::Strip, CubemapLayout::Cross].iter() {
+        let (columns, rows) = layout.image_size(1);
+        for (cell, face) in layout.face_cells().iter().zip(CUBE_FACES.iter()) {
+            let film = ((cell.0 as f32 + 0.5) / columns as f32, (cell.1 as f32 + 0.5) / rows as f32);
+            assert!(close(dir(Projection::Cubemap(layout), film, columns as f32 / rows as f32).unwrap(), face.forward));
+        }
+    }
+    assert!(Projection::Cubemap(CubemapLayout::Cross).ray((0.1, 0.1), 4. / 3.).is_none());
+    // both halves of an omni-directional stereo panorama look forwards from
+    // the middle, from eyes either side of the camera
+    let ods = Projection::OmniStereo { ipd: 0.064 };
+    let (left, left_dir) = ods.ray((0.5, 0.25), 1.).unwrap();
+    let (right, right_dir) = ods.ray((0.5, 0.75), 1.).unwrap();
+    assert!(close(left_dir, forward) && close(right_dir, forward));
+    assert!(close(left, Vec3 {x: -0.032, y: 0., z: 0.}) && close(right, Vec3 {x: 0.032, y: 0., z: 0.}));
+    // and looking right, the left eye is in front
+    let (left, _) = ods.ray((0.75, 0.25), 1.).unwrap();
+    assert!(close(left, Vec3 {x: 0., y: 0., z: -0.032}));
+    let buffer: Vec<Color3f> = (0..12*2).map(|i| Color3f {r: i as f32, g: 0., b: 0.}).collect();
+    let faces = cubemap_faces(CubemapLayout::Strip, (12, 2), &buffer);
+    assert_eq!(faces[1].0, "bk");
+    assert_eq!(faces[1].1.iter().map(|c| c.r).collect::<Vec<f32>>(), vec![2., 3., 14., 15.]);
+}
@@ -3,7 +3,12 @@ use std::ops::{Mul};
 
 use vec3::Vec3;
 
-#[derive(PartialEq, Clone, Copy)]
+/* Rotation quaternion a + bi + cj + dk, with 'a' the scalar part.
+ * Matrices are row-major and act on column vectors, matching vmul.
+ * Euler angles are (pitch, yaw, roll) about the x, y and z axes, applied
+ * as roll first, then pitch, then yaw.
+ * Note p * q is the rotation p followed by q. */
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub struct Quaternion {
     pub a: f32,
     pub b: f32,
@@ -26,6 +31,114 @@ impl Quaternion {
             d: axis.z*s
         }
     }
+    pub fn dot(&self, other: &Quaternion) -> f32 {
+        self.a*other.a + self.b*other.b + self.c*other.c + self.d*other.d
+    }
+    pub fn length(&self) -> f32 { f32::sqrt(self.dot(self)) }
+    pub fn smul(&self, s: f32) -> Quaternion {
+        Quaternion{a: self.a*s, b: self.b*s, c: self.c*s, d: self.d*s}
+    }
+    pub fn normal(&self) -> Quaternion {
+        self.smul(1.0 / self.length())
+    }
+    pub fn conjugate(&self) -> Quaternion {
+        Quaternion{a: self.a, b: -self.b, c: -self.c, d: -self.d}
+    }
+    pub fn inverse(&self) -> Quaternion {
+        self.conjugate().smul(1.0 / self.dot(self))
+    }
+    fn add(&self, other: &Quaternion) -> Quaternion {
+        Quaternion{a: self.a+other.a, b: self.b+other.b, c: self.c+other.c, d: self.d+other.d}
+    }
+    // 'other' negated if needed so interpolation takes the shortest arc
+    fn nearest(&self, other: &Quaternion) -> Quaternion {
+        if self.dot(other) < 0. { other.smul(-1.) } else { *other }
+    }
+    /* Normalised linear interpolation. Cheaper than slerp but not constant angular velocity */
+    pub fn nlerp(&self, other: &Quaternion, t: f32) -> Quaternion {
+        self.smul(1.-t).add(&self.nearest(other).smul(t)).normal()
+    }
+    /* Spherical linear interpolation between two unit quaternions, taking the shortest arc */
+    pub fn slerp(&self, other: &Quaternion, t: f32) -> Quaternion {
+        let o = self.nearest(other);
+        let cos_theta = self.dot(&o);
+        if cos_theta > 0.9995 {
+            // nearly parallel: avoid dividing by sin(theta) ~ 0
+            return self.nlerp(&o, t);
+        }
+        let theta = cos_theta.acos();
+        let sin_theta = theta.sin();
+        self.smul(((1.-t)*theta).sin() / sin_theta).add(&o.smul((t*theta).sin() / sin_theta)).normal()
+    }
+    pub fn to_matrix3(&self) -> [[f32; 3]; 3] {
+        let (w, x, y, z) = (self.a, self.b, self.c, self.d);
+        [[1.-2.*(y*y+z*z), 2.*(x*y-w*z),    2.*(x*z+w*y)],
+         [2.*(x*y+w*z),    1.-2.*(x*x+z*z), 2.*(y*z-w*x)],
+         [2.*(x*z-w*y),    2.*(y*z+w*x),    1.-2.*(x*x+y*y)]]
+    }
+    pub fn to_matrix4(&self) -> [[f32; 4]; 4] {
+        let m = self.to_matrix3();
+        [[m[0][0], m[0][1], m[0][2], 0.],
+         [m[1][0], m[1][1], m[1][2], 0.],
+         [m[2][0], m[2][1], m[2][2], 0.],
+         [0., 0., 0., 1.]]
+    }
+    /* From a pure rotation matrix */
+    pub fn from_matrix3(m: &[[f32; 3]; 3]) -> Quaternion {
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        // pick the largest component to divide by, for numerical stability
+        let q = if trace > 0. {
+            let s = 0.5 / (trace + 1.).sqrt();
+            Quaternion{a: 0.25/s, b: (m[2][1]-m[1][2])*s, c: (m[0][2]-m[2][0])*s, d: (m[1][0]-m[0][1])*s}
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = 2. * (1. + m[0][0] - m[1][1] - m[2][2]).sqrt();
+            Quaternion{a: (m[2][1]-m[1][2])/s, b: 0.25*s, c: (m[0][1]+m[1][0])/s, d: (m[0][2]+m[2][0])/s}
+        } else if m[1][1] > m[2][2] {
+            let s = 2. * (1. + m[1][1] - m[0][0] - m[2][2]).sqrt();
+            Quaternion{a: (m[0][2]-m[2][0])/s, b: (m[0][1]+m[1][0])/s, c: 0.25*s, d: (m[1][2]+m[2][1])/s}
+        } else {
+            let s = 2. * (1. + m[2][2] - m[0][0] - m[1][1]).sqrt();
+            Quaternion{a: (m[1][0]-m[0][1])/s, b: (m[0][2]+m[2][0])/s, c: (m[1][2]+m[2][1])/s, d: 0.25*s}
+        };
+        q.normal()
+    }
+    /* From the rotation part (upper-left 3x3) of a 4x4 transform */
+    pub fn from_matrix4(m: &[[f32; 4]; 4]) -> Quaternion {
+        Quaternion::from_matrix3(&[[m[0][0], m[0][1], m[0][2]],
+                                   [m[1][0], m[1][1], m[1][2]],
+                                   [m[2][0], m[2][1], m[2][2]]])
+    }
+    pub fn from_euler(pitch: f32, yaw: f32, roll: f32) -> Quaternion {
+        Quaternion::from_axis_angle(&Vec3{x:0., y:0., z:1.}, roll) *
+        Quaternion::from_axis_angle(&Vec3{x:1., y:0., z:0.}, pitch) *
+        Quaternion::from_axis_angle(&Vec3{x:0., y:1., z:0.}, yaw)
+    }
+    /* Inverse of from_euler, returning (pitch, yaw, roll). At +-90 degrees pitch roll is reported as 0 */
+    pub fn to_euler(&self) -> (f32, f32, f32) {
+        let m = self.to_matrix3();
+        let pitch = (-m[1][2]).max(-1.).min(1.).asin();
+        if m[1][2].abs() < 0.99999 {
+            (pitch, m[0][2].atan2(m[2][2]), m[1][0].atan2(m[1][1]))
+        } else {
+            (pitch, (-m[2][0]).atan2(m[0][0]), 0.)
+        }
+    }
+    /* Orientation that points a camera (looking down -z with +y up) along 'forward'.
+     * When forward is parallel to 'up' the roll is arbitrary, so another up is chosen */
+    pub fn look_rotation(forward: &Vec3, up: &Vec3) -> Quaternion {
+        let f = forward.normal();
+        let mut side = f.cross(up);
+        if side.length() < 1e-6 * up.length() {
+            // any axis not parallel to forward will do
+            let fallback = if f.x.abs() < 0.9 { Vec3{x:1., y:0., z:0.} } else { Vec3{x:0., y:0., z:1.} };
+            side = f.cross(&fallback);
+        }
+        let r = side.normal();
+        let u = r.cross(&f);
+        Quaternion::from_matrix3(&[[r.x, u.x, -f.x],
+                                   [r.y, u.y, -f.y],
+                                   [r.z, u.z, -f.z]])
+    }
 }
 
 impl Default for Quaternion {
@@ -46,3 +159,90 @@ impl Mul for Quaternion {
         }
     }
 }
+
+#[cfg(test)]
+fn assert_vec_near(a: Vec3, b: Vec3) {
+    assert!((a - b).length() < 1e-4, "{:?} != {:?}", a, b);
+}
+#[cfg(test)]
+fn assert_rot_near(p: Quaternion, q: Quaternion) {
+    // q and -q are the same rotation
+    assert!(p.dot(&q).abs() > 0.9999, "{:?} != {:?}", p, q);
+}
+
+#[test]
+fn test_quaternion_inverse() {
+    let q = Quaternion::from_axis_angle(&Vec3{x:1., y:2., z:-1.}.normal(), 0.7);
+    let v = Vec3{x:0.3, y:-2., z:5.};
+    assert_vec_near(q.inverse().vmul(&q.vmul(&v)), v);
+    assert_rot_near(q * q.conjugate(), Quaternion::default());
+    let scaled = q.smul(3.);
+    assert!((scaled.normal().length() - 1.).abs() < 1e-6);
+    assert_rot_near(scaled * scaled.inverse(), Quaternion::default());
+    // composition order
+    let p = Quaternion::from_axis_angle(&Vec3{x:0., y:0., z:1.}, 1.1);
+    assert_vec_near((p * q).vmul(&v), q.vmul(&p.vmul(&v)));
+}
+
+#[test]
+fn test_quaternion_interpolation() {
+    let y = Vec3{x:0., y:1., z:0.};
+    let p = Quaternion::from_axis_angle(&y, 0.2);
+    let q = Quaternion::from_axis_angle(&y, 1.4);
+    assert_rot_near(p.slerp(&q, 0.), p);
+    assert_rot_near(p.slerp(&q, 1.), q);
+    assert_rot_near(p.slerp(&q, 0.25), Quaternion::from_axis_angle(&y, 0.5));
+    // shortest arc even when q is given as its negation
+    assert_rot_near(p.slerp(&q.smul(-1.), 0.25), Quaternion::from_axis_angle(&y, 0.5));
+    assert_rot_near(p.nlerp(&q, 0.5), Quaternion::from_axis_angle(&y, 0.8));
+}
+
+#[test]
+fn test_quaternion_matrix() {
+    let q = Quaternion::from_axis_angle(&Vec3{x:-3., y:1., z:2.}.normal(), 2.9);
+    let m = q.to_matrix3();
+    let v = Vec3{x:1., y:2., z:3.};
+    let mv = Vec3{x: m[0][0]*v.x + m[0][1]*v.y + m[0][2]*v.z,
+                  y: m[1][0]*v.x + m[1][1]*v.y + m[1][2]*v.z,
+                  z: m[2][0]*v.x + m[2][1]*v.y + m[2][2]*v.z};
+    assert_vec_near(mv, q.vmul(&v));
+    assert_rot_near(Quaternion::from_matrix3(&m), q);
+    assert_rot_near(Quaternion::from_matrix4(&q.to_matrix4()), q);
+    // half turns exercise the non-positive trace branches
+    for axis in [Vec3{x:1., y:0., z:0.}, Vec3{x:0., y:1., z:0.}, Vec3{x:0., y:0., z:1.}].iter() {
+        let half = Quaternion::from_axis_angle(axis, f32::consts::PI);
+        assert_rot_near(Quaternion::from_matrix3(&half.to_matrix3()), half);
+    }
+}
+
+#[test]
+fn test_quaternion_euler() {
+    let (pitch, yaw, roll) = Quaternion::from_euler(0.3, -1.2, 0.5).to_euler();
+    assert!((pitch - 0.3).abs() < 1e-4 && (yaw + 1.2).abs() < 1e-4 && (roll - 0.5).abs() < 1e-4);
+    // yaw turns about +y: -z forward swings towards -x
+    let q = Quaternion::from_euler(0., 0.5*f32::consts::PI, 0.);
+    assert_vec_near(q.vmul(&Vec3{x:0., y:0., z:-1.}), Vec3{x:-1., y:0., z:0.});
+    let (pitch, _, roll) = Quaternion::from_euler(0.5*f32::consts::PI, 0.4, 0.).to_euler();
+    assert!((pitch - 0.5*f32::consts::PI).abs() < 1e-3 && roll == 0.);
+}
+
+#[test]
+fn test_quaternion_look_rotation() {
+    let forward = Vec3{x:1., y:-1., z:-2.}.normal();
+    let up = Vec3{x:0., y:1., z:0.};
+    let q = Quaternion::look_rotation(&forward, &up);
+    assert_vec_near(q.vmul(&Vec3{x:0., y:0., z:-1.}), forward);
+    assert!(q.vmul(&up).dot(&up) > 0.);
+    assert!(q.vmul(&Vec3{x:1., y:0., z:0.}).dot(&up).abs() < 1e-5);
+    assert_rot_near(Quaternion::look_rotation(&Vec3{x:0., y:0., z:-1.}, &up), Quaternion::default());
+}
+
+#[test]
+fn test_quaternion_look_rotation_along_up() {
+    let up = Vec3{x:0., y:1., z:0.};
+    for forward in [up, -up, Vec3{x:0., y:-3., z:0.}].iter() {
+        let q = Quaternion::look_rotation(forward, &up);
+        assert!(!(q.a.is_nan() || q.b.is_nan() || q.c.is_nan() || q.d.is_nan()));
+        assert_vec_near(q.vmul(&Vec3{x:0., y:0., z:-1.}), forward.normal());
+    }
+}
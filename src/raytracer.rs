@@ -1,9 +1,16 @@
 extern crate rand;
-use rand::Rng; // why did i need this for rng.gen?
 use std::default::Default;
+use std::sync::Arc;
 use vec3::Vec3;
 use color3f::Color3f;
 use quaternion::Quaternion;
+use heightfield::Heightfield;
+use animation::Track;
+use sampler::{Sampler,SamplerKind};
+use film::Filter;
+use light::Light;
+use volume::Medium;
+use projection::Projection;
 use shaders;
 
 pub const EPSILON: f32 = 0.0001;
@@ -11,14 +18,68 @@ pub const EPSILON: f32 = 0.0001;
 #[derive(Clone,Copy,Default)]
 pub struct Ray {
     pub origin: Vec3,
-    pub dir: Vec3
+    pub dir: Vec3,
+    // moment within the camera shutter interval this ray's path is sampled at
+    pub time: f32,
+    // hero wavelength in nm when rendering spectrally, otherwise 0
+    pub wavelength: f32,
+    // set once a path's direction has depended on its wavelength, leaving
+    // only the hero wavelength's light valid
+    pub single_wavelength: bool
+}
+
+impl Ray {
+    /* Distances along the ray, nearest first, to where its line enters and
+     * leaves the sphere, or None if it misses. Either may be behind the
+     * origin, and 'dir' is assumed normalized */
+    pub fn sphere_intersections(&self, center: &Vec3, radius: f32) -> Option<(f32, f32)> {
+        let v = self.origin - *center;
+        let b = -(v.dot(&self.dir));
+        let sq_det = (b*b) - v.dot(&v) + radius*radius;
+        if sq_det > 0. {
+            let det = sq_det.sqrt();
+            Some((b - det, b + det))
+        } else {
+            None
+        }
+    }
 }
 
 pub struct RenderConfig {
     pub threads: usize,
     pub samples_per_first_isect: u32,
     pub image_size: (u32, u32),
-    pub preview_hdr_gamma: f32
+    pub preview_hdr_gamma: f32,
+    pub sampler: SamplerKind,
+    pub filter: Filter,
+    pub adaptive: Option<AdaptiveConfig>,
+    // trace wavelengths rather than RGB, converting back through CIE XYZ
+    pub spectral: bool,
+    // binary PPM, such as the preview dumps, to compare the render with in the
+    // preview's split view
+    pub reference_image: Option<String>
+}
+
+/* Once every pixel has had min_iterations passes, stop tracing pixels whose
+ * relative standard error is below target_error, and stop rendering when
+ * none are left or time_budget_secs has passed. Pixels still traced get the
+ * usual samples_per_first_isect per pass: the samples converged pixels no
+ * longer take aren't handed on, passes just get quicker so more of them fit
+ * in the time budget */
+#[derive(Clone,Copy,Debug)]
+pub struct AdaptiveConfig {
+    pub min_iterations: u32,
+    pub target_error: f32,
+    pub time_budget_secs: Option<f32>
+}
+impl Default for AdaptiveConfig {
+    fn default() -> AdaptiveConfig {
+        AdaptiveConfig{
+            min_iterations: 4,
+            target_error: 0.02,
+            time_budget_secs: None
+        }
+    }
 }
 impl Default for RenderConfig {
     fn default() -> RenderConfig {
@@ -26,7 +87,12 @@ impl Default for RenderConfig {
             threads: 8,
             samples_per_first_isect: 20,
             image_size: (512, 512),
-            preview_hdr_gamma: 100.0
+            preview_hdr_gamma: 100.0,
+            sampler: SamplerKind::Sobol,
+            filter: Filter::default(),
+            adaptive: None,
+            spectral: false,
+            reference_image: None
         }
     }
 }
@@ -36,9 +102,41 @@ pub enum Primitive {
     Sphere(Vec3, f32),
     Triangle(Vec3, Vec3, Vec3),
     Plane(Vec3, Vec3),  // (origin, normal)
+    Heightfield(Arc<Heightfield>),
+    Moving(Box<Primitive>, Motion),  // translated only, never rotated
     ScatterEvent
 }
 
+impl Primitive {
+    pub fn normal_at(&self, hit_pos: &Vec3, ray: &Ray) -> Vec3 {
+        match *self {
+            Primitive::Sphere(origin, _) => (*hit_pos - origin).normal(),
+            Primitive::Triangle(v1, v2, v3) => (v2-v1).cross(&(v2-v3)).normal(),
+            Primitive::Plane(_, normal) => normal,
+            Primitive::Heightfield(ref hf) => hf.normal_at(hit_pos),
+            Primitive::Moving(ref prim, ref motion) => prim.normal_at(&(*hit_pos - motion.offset_at(ray.time)), ray),
+            Primitive::ScatterEvent => -ray.dir.normal()
+        }
+    }
+}
+
+/* Translation of a moving primitive over time.
+ * Primitives only translate: there is no rotation, so spinning objects don't blur. */
+#[derive(Clone)]
+pub enum Motion {
+    Linear(Vec3),  // velocity
+    Keyframed(Track<Vec3>)
+}
+
+impl Motion {
+    pub fn offset_at(&self, time: f32) -> Vec3 {
+        match *self {
+            Motion::Linear(velocity) => velocity.smul(time),
+            Motion::Keyframed(ref track) => track.at(time)
+        }
+    }
+}
+
 impl Default for Primitive {
     fn default() -> Primitive {
         Primitive::Sphere(Vec3::default(), 0.)
@@ -56,14 +154,42 @@ pub struct ColorProgramResult {
     pub emissive: Color3f
 }
 
-pub type PathProgram = fn(&RayIsect, &mut rand::ThreadRng) -> Option<Ray>;
+pub type PathProgram = fn(&RayIsect, &mut Sampler) -> Option<Ray>;
 pub type ColorProgram = fn(&RayIsect) -> ColorProgramResult;
-pub type VacuumProgram = fn(&RayIsect, &mut rand::ThreadRng) -> VacuumAction;
+pub type VacuumProgram = fn(&RayIsect, &mut Sampler) -> VacuumAction;
+// reflectance towards the eye of light arriving from 'dir', as a fraction of the
+// colour program's transmissive colour, including the cosine term
+pub type LightProgram = fn(&RayIsect, &Vec3) -> f32;
 
 pub struct Material {
     pub color_program: ColorProgram,
     pub path_program: PathProgram,
-    pub vacuum_program: Option<VacuumProgram>
+    pub vacuum_program: Option<VacuumProgram>,
+    // materials with a light program sample the scene's lights directly
+    pub light_program: Option<LightProgram>,
+    // values for programs that read them through isect.scene_obj.mat. Only
+    // materials with params can be tuned on objects picked in the preview window
+    pub params: Option<MaterialParams>
+}
+
+/* Parameters of a material's programs, as used by the shaders module's
+ * params_cp, glossy_pp and dielectric_pp */
+#[derive(Clone,Copy,Debug)]
+pub struct MaterialParams {
+    pub color: Color3f,
+    // spread of glossy reflection about the mirror direction. 0 is a perfect mirror
+    pub roughness: f32,
+    pub ior: f32
+}
+
+impl Default for MaterialParams {
+    fn default() -> MaterialParams {
+        MaterialParams {
+            color: Color3f {r: 0.8, g: 0.8, b: 0.8},
+            roughness: 0.,
+            ior: 1.5
+        }
+    }
 }
 
 impl Clone for Material {
@@ -71,13 +197,15 @@ impl Clone for Material {
         Material {
             path_program: self.path_program,
             color_program: self.color_program,
-            vacuum_program: None
+            vacuum_program: self.vacuum_program,
+            light_program: self.light_program,
+            params: self.params
         }
     }
 }
 
-fn default_path_program(isect: &RayIsect, rng: &mut rand::ThreadRng) -> Option<Ray> { None }
-fn default_color_program(isect: &RayIsect) -> ColorProgramResult {
+fn default_path_program(_isect: &RayIsect, _rng: &mut Sampler) -> Option<Ray> { None }
+fn default_color_program(_isect: &RayIsect) -> ColorProgramResult {
     ColorProgramResult { emissive: Color3f::default(), transmissive: Color3f::default() }
 }
 impl Default for Material {
@@ -85,25 +213,58 @@ impl Default for Material {
         Material {
             color_program: default_color_program,
             path_program: default_path_program,
-            vacuum_program: None
+            vacuum_program: None,
+            light_program: None,
+            params: None
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Scene {
-    pub objs: Vec<SceneObj>
+    pub objs: Vec<SceneObj>,
+    pub lights: Vec<Light>
 }
 
-#[derive(Copy,Clone)]
+#[derive(Clone,Default)]
 pub struct Camera {
     pub position: Vec3,
-    pub orientation: Quaternion
+    pub orientation: Quaternion,
+    // eye rays are given times spread uniformly between shutter open and close
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    pub projection: Projection
+}
+
+impl Camera {
+    /* Left and right eye cameras, 'ipd' apart. Perspective views are sheared
+     * off axis to converge at distance 'convergence', where things appear at
+     * the depth of the screen; other projections' eyes just look parallel */
+    pub fn stereo_pair(&self, ipd: f32, convergence: f32) -> (Camera, Camera) {
+        let eye = |side: f32| {
+            let projection = match self.projection {
+                Projection::Perspective { fov, shift } => {
+                    let half_height = (0.5 * fov).tan();
+                    Projection::Perspective { fov: fov, shift: (shift.0 - side * 0.5 * ipd / (convergence * half_height), shift.1) }
+                }
+                ref projection => projection.clone()
+            };
+            Camera {
+                position: self.position + self.orientation.vmul(&Vec3 {x: side * 0.5 * ipd, y: 0., z: 0.}),
+                projection: projection,
+                ..self.clone()
+            }
+        };
+        (eye(-1.), eye(1.))
+    }
 }
 
 #[derive(Clone,Default)]
 pub struct SceneObj {
     pub prim: Primitive,
-    pub mat: Material
+    pub mat: Material,
+    // fills a closed prim, whose surface (and material) rays then pass through unseen
+    pub medium: Option<Arc<Medium>>
 }
 
 #[derive(Clone,Copy)]
@@ -122,18 +283,42 @@ impl<'a> RayIsect<'a> {
         self.ray.origin + self.ray.dir.smul(self.dist)
     }
     pub fn normal(&self) -> Vec3 {
-        match self.scene_obj.prim {
-            Primitive::Sphere(origin, _) => (self.hit_pos() - origin).normal(),
-            Primitive::Triangle(v1, v2, v3) => (v2-v1).cross(&(v2-v3)).normal(),
-            Primitive::Plane(_, normal) => normal,
-            Primitive::ScatterEvent => -self.ray.dir.normal()
+        match self.scene_obj.medium {
+            Some(_) => -self.ray.dir.normal(),
+            None => self.scene_obj.prim.normal_at(&self.hit_pos(), &self.ray)
+        }
+    }
+    /* Colour, next ray and direct light response at this isect, from the
+     * material's programs. Rays never stop at the surface of an object with a
+     * medium, so isects with one are collisions inside it, and scatter by the
+     * medium's phase function */
+    pub fn color(&self) -> ColorProgramResult {
+        match self.scene_obj.medium {
+            Some(ref medium) => ColorProgramResult { transmissive: medium.scattering_color(self.ray.wavelength), emissive: Color3f::black() },
+            None => (self.scene_obj.mat.color_program)(self)
+        }
+    }
+    pub fn next_ray(&self, rng: &mut Sampler) -> Option<Ray> {
+        match self.scene_obj.medium {
+            Some(ref medium) => Some(Ray {origin: self.hit_pos(), dir: medium.phase.sample(&self.ray.dir, rng), ..self.ray}),
+            None => (self.scene_obj.mat.path_program)(self, rng)
+        }
+    }
+    pub fn samples_lights(&self) -> bool {
+        self.scene_obj.medium.is_some() || self.scene_obj.mat.light_program.is_some()
+    }
+    pub fn light_response(&self, dir: &Vec3) -> f32 {
+        match (&self.scene_obj.medium, self.scene_obj.mat.light_program) {
+            (&Some(ref medium), _) => medium.phase.eval(self.ray.dir.dot(dir)),
+            (&None, Some(light_program)) => light_program(self, dir),
+            (&None, None) => 0.
         }
     }
-    pub fn new_random_ray(&self, rng: &mut rand::ThreadRng) -> Ray {
+    pub fn new_random_ray(&self, rng: &mut Sampler) -> Ray {
         let last_isect_norm = self.normal();
         let ray_start_pos = self.hit_pos() + last_isect_norm.smul(EPSILON);
         let rand_dir = shaders::random_vector_in_hemisphere(&last_isect_norm, rng);
-        Ray {origin: ray_start_pos, dir: rand_dir}
+        Ray {origin: ray_start_pos, dir: rand_dir, ..self.ray}
     }
 }
 
@@ -143,7 +328,12 @@ pub struct Path<'a> {
     pub num_bounces: i32,
     pub isects: [RayIsect<'a>; MAX_BOUNCES],
     // cached first isect color result. we re-use first isect!
-    pub first_isect_color: Option<ColorProgramResult>
+    pub first_isect_color: Option<ColorProgramResult>,
+    // light sampled directly at each isect with a light program, before its transmissive colour
+    pub direct_light: [Color3f; MAX_BOUNCES],
+    // radiance of a visible light the path ended on
+    pub light_hit: Option<Color3f>,
+    pub single_wavelength: bool
 }
 
 #[test]
@@ -160,8 +350,49 @@ fn test_vec3() {
     assert_eq!(x.normal().length(), 1.0);
 }
 
+#[test]
+fn test_motion() {
+    let keys = Motion::Keyframed(Track::new(vec![(0., Vec3{x:0., y:0., z:0.}), (1., Vec3{x:2., y:0., z:0.}), (2., Vec3{x:2., y:4., z:0.})]));
+    assert_eq!(keys.offset_at(-1.), Vec3{x:0., y:0., z:0.});
+    assert_eq!(keys.offset_at(0.5), Vec3{x:1., y:0., z:0.});
+    assert_eq!(keys.offset_at(1.5), Vec3{x:2., y:2., z:0.});
+    assert_eq!(keys.offset_at(3.), Vec3{x:2., y:4., z:0.});
+    assert_eq!(Motion::Linear(Vec3{x:0., y:2., z:0.}).offset_at(0.25), Vec3{x:0., y:0.5, z:0.});
+}
+
 #[test]
 fn test_color3f() {
     let c = Color3f {r: 1.0, g: 0.5, b: 0.25};
     assert_eq!(c+c, Color3f { r: 2.0, g: 1.0, b: 0.5});
 }
+
+#[test]
+fn test_stereo_pair() {
+    use projection::RayGenerator;
+    let camera = Camera { position: Vec3 {x: 1., y: 2., z: 3.}, ..Camera::default() };
+    let (left, right) = camera.stereo_pair(0.064, 2.);
+    assert!((left.position - Vec3 {x: 0.968, y: 2., z: 3.}).length() < 1e-5);
+    assert!((right.position - Vec3 {x: 1.032, y: 2., z: 3.}).length() < 1e-5);
+    // both eyes see the point at the convergence distance in the middle of their film
+    for eye in vec![left, right] {
+        let (_, dir) = eye.projection.ray((0.5, 0.5), 1.5).unwrap();
+        let target = eye.position + dir.smul(2. / -dir.z);
+        assert!((target - Vec3 {x: 1., y: 2., z: 1.}).length() < 1e-5);
+    }
+}
+
+#[test]
+fn test_material_clone() {
+    fn thin_air_vp(_: &RayIsect, _: &mut Sampler) -> VacuumAction {
+        VacuumAction::Continue
+    }
+    let mat = Material {
+        vacuum_program: Some(thin_air_vp),
+        params: Some(MaterialParams { ior: 1.33, ..MaterialParams::default() }),
+        ..Material::default()
+    };
+    let copy = mat.clone();
+    let expected: VacuumProgram = thin_air_vp;
+    assert!(copy.vacuum_program.map(|vp| vp as usize) == Some(expected as usize));
+    assert_eq!(copy.params.map(|p| p.ior), Some(1.33));
+}
@@ -0,0 +1,246 @@
+extern crate rand;
+use rand::Rng;
+
+/* Source of sample values for a path. Each path is a sample (index) within a
+ * pixel, and successive calls to next_1d/next_2d draw successive dimensions of
+ * that sample from a low-discrepancy sequence, so that over many samples each
+ * pixel's sub-pixel positions, bounce directions etc. cover their domains evenly.
+ * Sampler also implements rand::Rng for decisions that want plain random numbers. */
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum SamplerKind {
+    Random,
+    Stratified(u32),  // jittered strata, cycling every n samples
+    Halton,
+    Sobol
+}
+
+// sub-pixel offset (2D), shutter time (1D), wavelength (1D) then lens position (2D)
+pub const EYE_RAY_DIMENSIONS: u32 = 6;
+
+pub struct Sampler {
+    pub kind: SamplerKind,
+    rng: rand::ThreadRng,
+    pixel: u32,
+    index: u32,
+    dimension: u32
+}
+
+static PRIMES: [u32; 32] = [2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+                            59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131];
+
+impl Sampler {
+    pub fn new(kind: SamplerKind, rng: rand::ThreadRng) -> Sampler {
+        Sampler { kind: kind, rng: rng, pixel: 0, index: 0, dimension: 0 }
+    }
+
+    pub fn start_pixel_sample(&mut self, pixel: u32, index: u32) {
+        self.pixel = pixel;
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    pub fn skip_dimensions(&mut self, n: u32) {
+        self.dimension += n;
+    }
+
+    // per pixel and dimension, so a pixel's samples stay well distributed across iterations
+    fn seed(&self) -> u32 {
+        hash(self.pixel ^ hash(self.dimension))
+    }
+
+    pub fn next_1d(&mut self) -> f32 {
+        let seed = self.seed();
+        let v = match self.kind {
+            SamplerKind::Random => self.rng.gen::<f32>(),
+            SamplerKind::Stratified(n) => {
+                let n = n.max(1);
+                let stratum = permutation_element(self.index % n, n, hash(seed ^ (self.index / n)));
+                (stratum as f32 + self.rng.gen::<f32>()) / n as f32
+            }
+            SamplerKind::Halton => {
+                if (self.dimension as usize) < PRIMES.len() {
+                    // randomly rotated per pixel so neighbours are decorrelated
+                    let v = radical_inverse(PRIMES[self.dimension as usize], self.index) + bits_to_float(seed);
+                    if v >= 1. { v - 1. } else { v }
+                } else {
+                    self.rng.gen::<f32>()
+                }
+            }
+            SamplerKind::Sobol => {
+                let i = nested_uniform_scramble(self.index, seed);
+                bits_to_float(nested_uniform_scramble(i.reverse_bits(), hash(seed)))
+            }
+        };
+        self.dimension += 1;
+        v.min(ONE_MINUS_EPSILON)
+    }
+
+    pub fn next_2d(&mut self) -> (f32, f32) {
+        let seed = self.seed();
+        let v = match self.kind {
+            SamplerKind::Random => (self.rng.gen::<f32>(), self.rng.gen::<f32>()),
+            SamplerKind::Stratified(n) => {
+                let k = (n.max(1) as f32).sqrt().ceil() as u32;
+                let cells = k*k;
+                let stratum = permutation_element(self.index % cells, cells, hash(seed ^ (self.index / cells)));
+                (((stratum % k) as f32 + self.rng.gen::<f32>()) / k as f32,
+                 ((stratum / k) as f32 + self.rng.gen::<f32>()) / k as f32)
+            }
+            SamplerKind::Halton => {
+                let x = self.next_1d();
+                let y = self.next_1d();
+                self.dimension -= 2;
+                (x, y)
+            }
+            SamplerKind::Sobol => {
+                // first two Sobol dimensions, index shuffled and values Owen scrambled per pixel
+                let i = nested_uniform_scramble(self.index, seed);
+                (bits_to_float(nested_uniform_scramble(i.reverse_bits(), hash(seed ^ 1))),
+                 bits_to_float(nested_uniform_scramble(sobol_dimension_2(i), hash(seed ^ 2))))
+            }
+        };
+        self.dimension += 2;
+        (v.0.min(ONE_MINUS_EPSILON), v.1.min(ONE_MINUS_EPSILON))
+    }
+}
+
+impl Rng for Sampler {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+}
+
+const ONE_MINUS_EPSILON: f32 = 0.99999994;
+
+fn bits_to_float(bits: u32) -> f32 {
+    (bits >> 8) as f32 * (1. / (1u32 << 24) as f32)
+}
+
+/* Integer hash whose every output bit depends on every input bit, for
+ * scrambling sequences per pixel and for seeding procedural noise */
+pub fn hash(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+fn radical_inverse(base: u32, mut i: u32) -> f32 {
+    let inv_base = 1. / base as f64;
+    let mut inv_base_n = 1.;
+    let mut reversed: u64 = 0;
+    while i > 0 {
+        reversed = reversed * base as u64 + (i % base) as u64;
+        inv_base_n *= inv_base;
+        i /= base;
+    }
+    (reversed as f64 * inv_base_n) as f32
+}
+
+// second dimension of the Sobol sequence, as bits of a binary fraction
+fn sobol_dimension_2(mut i: u32) -> u32 {
+    let mut v: u32 = 1 << 31;
+    let mut result = 0;
+    while i != 0 {
+        if i & 1 != 0 {
+            result ^= v;
+        }
+        i >>= 1;
+        v ^= v >> 1;
+    }
+    result
+}
+
+// Burley 2020, "Practical Hash-based Owen Scrambling"
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// element i of a random permutation of 0..n chosen by 'seed' (Kensler 2013, "Correlated Multi-Jittered Sampling")
+fn permutation_element(mut i: u32, n: u32, seed: u32) -> u32 {
+    let mut w = n - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < n {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % n
+}
+
+#[cfg(test)]
+fn strata_covered(kind: SamplerKind, k: u32) -> bool {
+    // the first k*k samples of a pixel should land one in each cell of a k x k grid
+    let mut sampler = Sampler::new(kind, rand::thread_rng());
+    let mut hit = vec![false; (k*k) as usize];
+    for index in 0..k*k {
+        sampler.start_pixel_sample(1234, index);
+        sampler.skip_dimensions(EYE_RAY_DIMENSIONS);
+        let (x, y) = sampler.next_2d();
+        hit[((y * k as f32) as u32 * k + (x * k as f32) as u32) as usize] = true;
+    }
+    hit.iter().all(|&h| h)
+}
+
+#[test]
+fn test_sampler_stratification() {
+    assert!(strata_covered(SamplerKind::Sobol, 2));
+    assert!(strata_covered(SamplerKind::Sobol, 4));
+    assert!(strata_covered(SamplerKind::Stratified(16), 4));
+    let mut sampler = Sampler::new(SamplerKind::Stratified(8), rand::thread_rng());
+    let mut strata: Vec<u32> = (0..8).map(|i| {
+        sampler.start_pixel_sample(7, i);
+        (sampler.next_1d() * 8.) as u32
+    }).collect();
+    strata.sort();
+    assert_eq!(strata, (0..8).collect::<Vec<u32>>());
+}
+
+#[test]
+fn test_sampler_halton() {
+    assert_eq!(radical_inverse(2, 1), 0.5);
+    assert_eq!(radical_inverse(2, 6), 0.375);
+    assert!((radical_inverse(3, 5) - 7./9.).abs() < 1e-6);
+    let mut sampler = Sampler::new(SamplerKind::Halton, rand::thread_rng());
+    for index in 0..64 {
+        sampler.start_pixel_sample(3, index);
+        for _ in 0..40 {
+            let v = sampler.next_1d();
+            assert!(v >= 0. && v < 1.);
+        }
+    }
+}
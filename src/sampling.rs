@@ -0,0 +1,128 @@
+use std::f32;
+use std::f32::consts::PI;
+use vec3::Vec3;
+
+/* Warps of uniform random numbers u = (u1, u2) in [0..1) onto common domains.
+ * Each returns the sample and its probability density (per unit solid angle
+ * for directions, per unit area for points). */
+
+/* Orthonormal basis with 'n' as the local z axis */
+#[derive(Clone, Copy, Debug)]
+pub struct Onb {
+    pub u: Vec3,
+    pub v: Vec3,
+    pub n: Vec3
+}
+
+impl Onb {
+    pub fn from_normal(n: &Vec3) -> Onb {
+        // Duff et al. 2017, "Building an Orthonormal Basis, Revisited"
+        let sign = if n.z >= 0. { 1. } else { -1. };
+        let a = -1. / (sign + n.z);
+        let b = n.x * n.y * a;
+        Onb {
+            u: Vec3 {x: 1. + sign * n.x * n.x * a, y: sign * b, z: -sign * n.x},
+            v: Vec3 {x: b, y: sign + n.y * n.y * a, z: -n.y},
+            n: *n
+        }
+    }
+    pub fn to_world(&self, local: &Vec3) -> Vec3 {
+        self.u.smul(local.x) + self.v.smul(local.y) + self.n.smul(local.z)
+    }
+    pub fn to_local(&self, world: &Vec3) -> Vec3 {
+        Vec3 {x: world.dot(&self.u), y: world.dot(&self.v), z: world.dot(&self.n)}
+    }
+}
+
+/* Point on the unit disk, by Shirley's concentric mapping. pdf = 1/pi */
+pub fn concentric_disk(u: (f32, f32)) -> (f32, f32) {
+    let ox = 2. * u.0 - 1.;
+    let oy = 2. * u.1 - 1.;
+    if ox == 0. && oy == 0. {
+        return (0., 0.);
+    }
+    let (r, theta) = if ox.abs() > oy.abs() {
+        (ox, 0.25 * PI * (oy / ox))
+    } else {
+        (oy, 0.5 * PI - 0.25 * PI * (ox / oy))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+pub fn uniform_sphere(u: (f32, f32)) -> (Vec3, f32) {
+    let z = 1. - 2. * u.0;
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = 2. * PI * u.1;
+    (Vec3 {x: r * phi.cos(), y: r * phi.sin(), z: z}, 0.25 / PI)
+}
+
+pub fn uniform_hemisphere(normal: &Vec3, u: (f32, f32)) -> (Vec3, f32) {
+    let z = u.0;
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = 2. * PI * u.1;
+    let local = Vec3 {x: r * phi.cos(), y: r * phi.sin(), z: z};
+    (Onb::from_normal(normal).to_world(&local), 0.5 / PI)
+}
+
+/* Direction about 'normal' with density proportional to the cosine of its angle to it */
+pub fn cosine_hemisphere(normal: &Vec3, u: (f32, f32)) -> (Vec3, f32) {
+    let (x, y) = concentric_disk(u);
+    let z = (1. - x * x - y * y).max(0.).sqrt();
+    (Onb::from_normal(normal).to_world(&Vec3 {x: x, y: y, z: z}), z / PI)
+}
+
+/* Direction within the cone about 'axis' whose half-angle has cosine cos_max */
+pub fn uniform_cone(axis: &Vec3, cos_max: f32, u: (f32, f32)) -> (Vec3, f32) {
+    let z = 1. - u.0 * (1. - cos_max);
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = 2. * PI * u.1;
+    let local = Vec3 {x: r * phi.cos(), y: r * phi.sin(), z: z};
+    (Onb::from_normal(axis).to_world(&local), 1. / (2. * PI * (1. - cos_max)))
+}
+
+/* Point on a disk of 'radius' about 'center', facing along 'normal' */
+pub fn uniform_disk(center: &Vec3, normal: &Vec3, radius: f32, u: (f32, f32)) -> (Vec3, f32) {
+    let (x, y) = concentric_disk(u);
+    let local = Vec3 {x: x * radius, y: y * radius, z: 0.};
+    (*center + Onb::from_normal(normal).to_world(&local), 1. / (PI * radius * radius))
+}
+
+pub fn uniform_triangle(a: &Vec3, b: &Vec3, c: &Vec3, u: (f32, f32)) -> (Vec3, f32) {
+    let su = u.0.sqrt();
+    let (b0, b1) = (1. - su, u.1 * su);
+    let area = 0.5 * (*b - *a).cross(&(*c - *a)).length();
+    (a.smul(b0) + b.smul(b1) + c.smul(1. - b0 - b1), 1. / area)
+}
+
+#[test]
+fn test_onb() {
+    for n in [Vec3{x:0., y:0., z:1.}, Vec3{x:0., y:0., z:-1.}, Vec3{x:1., y:2., z:-3.}.normal()].iter() {
+        let b = Onb::from_normal(n);
+        assert!((b.u.length() - 1.).abs() < 1e-5 && (b.v.length() - 1.).abs() < 1e-5);
+        assert!(b.u.dot(&b.v).abs() < 1e-5 && b.u.dot(n).abs() < 1e-5 && b.v.dot(n).abs() < 1e-5);
+        let w = Vec3{x:0.3, y:-0.2, z:0.9};
+        assert!((b.to_world(&b.to_local(&w)) - w).length() < 1e-5);
+    }
+}
+
+#[test]
+fn test_sample_domains() {
+    let n = Vec3{x:0., y:1., z:0.};
+    for i in 0..16 {
+        for j in 0..16 {
+            let u = ((i as f32 + 0.5) / 16., (j as f32 + 0.5) / 16.);
+            let (d, pdf) = cosine_hemisphere(&n, u);
+            assert!((d.length() - 1.).abs() < 1e-4 && d.dot(&n) >= 0.);
+            assert!((pdf - d.dot(&n) / PI).abs() < 1e-4);
+            let (d, _) = uniform_hemisphere(&n, u);
+            assert!((d.length() - 1.).abs() < 1e-4 && d.dot(&n) >= 0.);
+            assert!((uniform_sphere(u).0.length() - 1.).abs() < 1e-4);
+            let (d, _) = uniform_cone(&n, 0.9, u);
+            assert!(d.dot(&n) >= 0.9 - 1e-5);
+            let (x, y) = concentric_disk(u);
+            assert!(x*x + y*y <= 1. + 1e-5);
+            let (p, pdf) = uniform_triangle(&Vec3{x:0., y:0., z:0.}, &Vec3{x:2., y:0., z:0.}, &Vec3{x:0., y:0., z:2.}, u);
+            assert!(p.x >= 0. && p.z >= 0. && p.x + p.z <= 2. + 1e-5 && (pdf - 0.5).abs() < 1e-5);
+        }
+    }
+}
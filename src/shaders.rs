@@ -1,59 +1,92 @@
-extern crate rand;
-use rand::Rng; // why did i need this for rng.gen?
-use raytracer::{EPSILON,Ray,RayIsect};
+use std::f32::consts::PI;
+use raytracer::{EPSILON,Ray,RayIsect,IsectFrom,ColorProgramResult,MaterialParams};
 use vec3::Vec3;
+use color3f::Color3f;
+use spectrum;
+use spectrum::Ior;
+use sampling;
+use sampler::Sampler;
 
-pub fn end_pp(isect: &RayIsect, rng: &mut rand::ThreadRng) -> Option<Ray> { None }
+pub fn end_pp(_isect: &RayIsect, _rng: &mut Sampler) -> Option<Ray> { None }
 
-pub fn mirror_pp(isect: &RayIsect, rng: &mut rand::ThreadRng) -> Option<Ray> {
+pub fn mirror_pp(isect: &RayIsect, _rng: &mut Sampler) -> Option<Ray> {
     let isect_normal = isect.normal();
     let isect_pos = isect.hit_pos();
-    let reflect = isect.ray.dir - (isect_normal.smul(isect.ray.dir.dot(&isect_normal))).smul(2.);
-    Some(Ray{origin: isect_pos + isect_normal.smul(EPSILON), dir: reflect.normal()})
+    Some(Ray{origin: isect_pos + isect_normal.smul(EPSILON), dir: isect.ray.dir.reflect(&isect_normal), ..isect.ray})
 }
 
-pub fn diffuse_pp(isect: &RayIsect, rng: &mut rand::ThreadRng) -> Option<Ray> {
+pub fn diffuse_pp(isect: &RayIsect, rng: &mut Sampler) -> Option<Ray> {
     let norm = isect.normal();
-    loop {
-        let new_ray_dir = random_vector_in_hemisphere(&norm, rng);
-        if rng.gen::<f32>() < new_ray_dir.dot(&norm) {
-            return Some(Ray{
-                origin: isect.hit_pos() + norm.smul(EPSILON),
-                dir: new_ray_dir
-            })
-        }
-    }
+    let (new_ray_dir, _) = sampling::cosine_hemisphere(&norm, rng.next_2d());
+    Some(Ray{
+        origin: isect.hit_pos() + norm.smul(EPSILON),
+        dir: new_ray_dir, ..isect.ray})
 }
 
-fn flip_vector_to_hemisphere(flipee: &Vec3, norm: &Vec3) -> Vec3 {
-    if flipee.dot(norm) > 0. {
-        *flipee
-    } else {
-        -*flipee
+/* Ray refracted through the surface of a dielectric, or reflected by total
+ * internal reflection. When rendering spectrally a dispersive 'ior' bends each
+ * wavelength differently, so the ray keeps only its hero wavelength */
+pub fn refracted_ray(isect: &RayIsect, ior: &Ior) -> Ray {
+    let n = ior.at(isect.ray.wavelength);
+    // (normal facing the incoming ray, ratio of refractive indices n1/n2)
+    let (norm, eta) = match isect.from {
+        IsectFrom::Outside => (isect.normal(), 1. / n),
+        IsectFrom::Inside => (-isect.normal(), n)
+    };
+    let isect_pos = isect.hit_pos();
+    match isect.ray.dir.refract(&norm, eta) {
+        Some(refract_dir) => Ray{
+            origin: isect_pos - norm.smul(EPSILON),
+            dir: refract_dir,
+            single_wavelength: isect.ray.single_wavelength || (isect.ray.wavelength > 0. && ior.is_dispersive()),
+            ..isect.ray
+        },
+        None => Ray{origin: isect_pos + norm.smul(EPSILON), dir: isect.ray.dir.reflect(&norm), ..isect.ray}
     }
 }
 
-pub fn random_vector_in_hemisphere(norm: &Vec3, rng: &mut rand::ThreadRng) -> Vec3 {
-    flip_vector_to_hemisphere(
-        &random_normal(rng),
-        norm
-    )
+/* Programs reading their material's params, so they can be tuned in the
+ * preview window. Materials without params get MaterialParams::default() */
+fn material_params(isect: &RayIsect) -> MaterialParams {
+    isect.scene_obj.mat.params.unwrap_or_default()
+}
+
+pub fn params_cp(isect: &RayIsect) -> ColorProgramResult {
+    ColorProgramResult { transmissive: material_params(isect).color, emissive: Color3f::black() }
+}
+
+/* Mirror reflection blurred by the material's roughness */
+pub fn glossy_pp(isect: &RayIsect, rng: &mut Sampler) -> Option<Ray> {
+    let norm = isect.normal();
+    let reflected = isect.ray.dir.reflect(&norm);
+    let roughness = material_params(isect).roughness;
+    let glossy = (reflected + random_normal(rng).smul(roughness)).normal();
+    // scattered below the surface, fall back to the mirror direction
+    let dir = if glossy.dot(&norm) > 0. { glossy } else { reflected };
+    Some(Ray{origin: isect.hit_pos() + norm.smul(EPSILON), dir: dir, ..isect.ray})
+}
+
+/* Clear dielectric of the material's index of refraction */
+pub fn dielectric_pp(isect: &RayIsect, _rng: &mut Sampler) -> Option<Ray> {
+    Some(refracted_ray(isect, &Ior::Constant(material_params(isect).ior)))
 }
 
-pub fn random_normal(rng: &mut rand::ThreadRng) -> Vec3 {
-    loop {
-        let v = Vec3 {x: 1.-2.*rng.gen::<f32>(), y: 1.0-2.*rng.gen::<f32>(), z: 1.-2.*rng.gen::<f32>()};
-        let len_sqr = v.x*v.x + v.y*v.y + v.z*v.z;
-        if len_sqr <= 1. {
-            return v.smul(1./len_sqr.sqrt());
-        }
+pub fn lambert_lp(isect: &RayIsect, dir: &Vec3) -> f32 {
+    isect.normal().dot(dir).max(0.) / PI
+}
+
+/* Colour program result for a black body glowing at 'kelvin', with luminance 'intensity' */
+pub fn blackbody_emission(kelvin: f32, intensity: f32) -> ColorProgramResult {
+    ColorProgramResult {
+        transmissive: Color3f::black(),
+        emissive: spectrum::blackbody(kelvin).smul(intensity)
     }
 }
 
-fn new_random_ray_from_isect(isect: &RayIsect, rng: &mut rand::ThreadRng) -> Ray {
-    let last_isect_norm = isect.normal();
-    let ray_start_pos = isect.hit_pos() + last_isect_norm.smul(EPSILON);
-    let rand_dir = random_vector_in_hemisphere(&last_isect_norm, rng);
-    Ray {origin: ray_start_pos, dir: rand_dir}
+pub fn random_vector_in_hemisphere(norm: &Vec3, rng: &mut Sampler) -> Vec3 {
+    sampling::uniform_hemisphere(norm, rng.next_2d()).0
 }
 
+pub fn random_normal(rng: &mut Sampler) -> Vec3 {
+    sampling::uniform_sphere(rng.next_2d()).0
+}
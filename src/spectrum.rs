@@ -0,0 +1,241 @@
+use std::f32;
+use color3f::Color3f;
+
+/* Conversions between light spectra and RGB. Wavelengths are in nanometres,
+ * and RGB is linear Rec.709 / sRGB primaries with a D65 white point. */
+
+pub const LAMBDA_MIN: f32 = 360.;
+pub const LAMBDA_MAX: f32 = 830.;
+
+fn piecewise_gaussian(lambda: f32, mu: f32, sigma_below: f32, sigma_above: f32) -> f32 {
+    let t = (lambda - mu) / if lambda < mu { sigma_below } else { sigma_above };
+    (-0.5 * t * t).exp()
+}
+
+/* CIE 1931 2° colour matching functions, by the multi-lobe fit of Wyman,
+ * Sloan & Shirley 2013, "Simple Analytic Approximations to the CIE XYZ
+ * Color Matching Functions" */
+pub fn cie_xyz(lambda: f32) -> (f32, f32, f32) {
+    let x = 1.056 * piecewise_gaussian(lambda, 599.8, 37.9, 31.0)
+          + 0.362 * piecewise_gaussian(lambda, 442.0, 16.0, 26.7)
+          - 0.065 * piecewise_gaussian(lambda, 501.1, 20.4, 26.2);
+    let y = 0.821 * piecewise_gaussian(lambda, 568.8, 46.9, 40.5)
+          + 0.286 * piecewise_gaussian(lambda, 530.9, 16.3, 31.1);
+    let z = 1.217 * piecewise_gaussian(lambda, 437.0, 11.8, 36.0)
+          + 0.681 * piecewise_gaussian(lambda, 459.0, 26.0, 13.8);
+    (x, y, z)
+}
+
+pub fn xyz_to_rgb(xyz: (f32, f32, f32)) -> Color3f {
+    let (x, y, z) = xyz;
+    Color3f {
+        r:  3.2404542*x - 1.5371385*y - 0.4985314*z,
+        g: -0.9692660*x + 1.8760108*y + 0.0415560*z,
+        b:  0.0556434*x - 0.2040259*y + 1.0572252*z
+    }
+}
+
+/* Spectral radiance of a black body, in W / (sr m^2 nm), by Planck's law */
+pub fn planck(lambda: f32, kelvin: f32) -> f32 {
+    const H: f64 = 6.62607015e-34;
+    const C: f64 = 2.99792458e8;
+    const K_B: f64 = 1.380649e-23;
+    let l = lambda as f64 * 1e-9;
+    let radiance = 2. * H * C * C / (l.powi(5) * ((H * C / (l * K_B * kelvin as f64)).exp() - 1.));
+    (radiance * 1e-9) as f32
+}
+
+/* Integrate a spectrum against the colour matching functions, in 5nm steps */
+pub fn spectrum_to_xyz<F>(spectrum: F) -> (f32, f32, f32) where F: Fn(f32) -> f32 {
+    const STEP: f32 = 5.;
+    let mut xyz = (0., 0., 0.);
+    let mut lambda = LAMBDA_MIN;
+    while lambda <= LAMBDA_MAX {
+        let s = spectrum(lambda) * STEP;
+        let (x, y, z) = cie_xyz(lambda);
+        xyz = (xyz.0 + x*s, xyz.1 + y*s, xyz.2 + z*s);
+        lambda += STEP;
+    }
+    xyz
+}
+
+/* Colour of a black body's emission in physical units: its spectral radiance
+ * weighted by the matching functions, so luminance is Y in W / (sr m^2).
+ * Channels a black body lies outside the RGB gamut in are clipped to zero */
+pub fn blackbody_radiance(kelvin: f32) -> Color3f {
+    xyz_to_rgb(spectrum_to_xyz(|lambda| planck(lambda, kelvin))).clamp(0., f32::INFINITY)
+}
+
+/* Bodies cooler than this hardly glow at all, and their radiance in f32
+ * underflows to zero, leaving no colour to normalise */
+pub const BLACKBODY_MIN_KELVIN: f32 = 500.;
+
+/* Colour of a black body scaled to unit luminance, or black below BLACKBODY_MIN_KELVIN */
+pub fn blackbody(kelvin: f32) -> Color3f {
+    if !(kelvin >= BLACKBODY_MIN_KELVIN) {
+        return Color3f::black();
+    }
+    let c = blackbody_radiance(kelvin);
+    c.smul(1. / c.luminance())
+}
+
+/* Hero wavelength sampling (Wilkie et al. 2014): each path carries a hero
+ * wavelength, and the others it estimates at the same time are spaced evenly
+ * after it, wrapping round the visible range */
+pub const HERO_WAVELENGTHS: usize = 4;
+
+pub fn sample_wavelength(u: f32) -> f32 {
+    LAMBDA_MIN + u * (LAMBDA_MAX - LAMBDA_MIN)
+}
+
+pub fn hero_wavelengths(hero: f32) -> [f32; HERO_WAVELENGTHS] {
+    let range = LAMBDA_MAX - LAMBDA_MIN;
+    let mut lambdas = [hero; HERO_WAVELENGTHS];
+    for i in 1..HERO_WAVELENGTHS {
+        let t = (hero - LAMBDA_MIN) / range + i as f32 / HERO_WAVELENGTHS as f32;
+        lambdas[i] = LAMBDA_MIN + (t - t.floor()) * range;
+    }
+    lambdas
+}
+
+/* Value at 'lambda' of a smooth spectrum for an RGB colour, as a blend of
+ * overlapping blue, green and red bands that sum to one everywhere. White
+ * upsamples to a flat spectrum and albedos in [0..1] stay in [0..1] */
+pub fn rgb_to_spectrum(c: &Color3f, lambda: f32) -> f32 {
+    let blue_green = smoothstep(480., 510., lambda);
+    let green_red = smoothstep(570., 600., lambda);
+    c.b * (1. - blue_green) + c.g * (blue_green - green_red) + c.r * green_red
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).max(0.).min(1.);
+    t * t * (3. - 2. * t)
+}
+
+/* Turns radiance estimates at uniformly sampled wavelengths into RGB. White
+ * balanced, so a flat spectrum (an upsampled RGB white) comes back as white */
+pub struct SpectrumToRgb {
+    y_integral: f32,
+    white: Color3f
+}
+
+impl SpectrumToRgb {
+    pub fn new() -> SpectrumToRgb {
+        let (x, y, z) = spectrum_to_xyz(|_| 1.);
+        SpectrumToRgb { y_integral: y, white: xyz_to_rgb((x / y, 1., z / y)) }
+    }
+
+    pub fn to_rgb(&self, lambdas: &[f32], values: &[f32]) -> Color3f {
+        let scale = (LAMBDA_MAX - LAMBDA_MIN) / (lambdas.len() as f32 * self.y_integral);
+        let mut xyz = (0., 0., 0.);
+        for (&lambda, &value) in lambdas.iter().zip(values.iter()) {
+            let (x, y, z) = cie_xyz(lambda);
+            xyz = (xyz.0 + x*value*scale, xyz.1 + y*value*scale, xyz.2 + z*value*scale);
+        }
+        let c = xyz_to_rgb(xyz);
+        Color3f {r: c.r / self.white.r, g: c.g / self.white.g, b: c.b / self.white.b}
+    }
+}
+
+/* Index of refraction of a dispersive medium */
+#[derive(Clone, Copy, Debug)]
+pub enum Ior {
+    Constant(f32),
+    // n = a + b / lambda^2, with b in nm^2
+    Cauchy { a: f32, b: f32 },
+    // n^2 = 1 + sum of b lambda^2 / (lambda^2 - c), with c in um^2
+    Sellmeier { b: [f32; 3], c: [f32; 3] }
+}
+
+pub const BK7_GLASS: Ior = Ior::Sellmeier {
+    b: [1.03961212, 0.231792344, 1.01046945],
+    c: [0.00600069867, 0.0200179144, 103.560653]
+};
+pub const FUSED_SILICA: Ior = Ior::Sellmeier {
+    b: [0.6961663, 0.4079426, 0.8974794],
+    c: [0.0046791482, 0.0135120631, 97.9340025]
+};
+
+impl Ior {
+    /* Index at 'lambda' nm. Rays not rendered spectrally have wavelength 0, and
+     * use the index at the sodium D line */
+    pub fn at(&self, lambda: f32) -> f32 {
+        let lambda = if lambda > 0. { lambda } else { 587.6 };
+        match *self {
+            Ior::Constant(n) => n,
+            Ior::Cauchy { a, b } => a + b / (lambda * lambda),
+            Ior::Sellmeier { b, c } => {
+                let l2 = (lambda * 1e-3) * (lambda * 1e-3);
+                (1. + (0..3).map(|i| b[i] * l2 / (l2 - c[i])).sum::<f32>()).sqrt()
+            }
+        }
+    }
+
+    pub fn is_dispersive(&self) -> bool {
+        match *self {
+            Ior::Constant(_) => false,
+            _ => true
+        }
+    }
+}
+
+#[test]
+fn test_cie_xyz() {
+    // luminous efficiency peaks near 555nm, and the functions integrate roughly equally
+    assert!((cie_xyz(555.).1 - 1.).abs() < 0.02);
+    let (x, y, z) = spectrum_to_xyz(|_| 1.);
+    assert!((x / y - 1.).abs() < 0.02 && (z / y - 1.).abs() < 0.02);
+    let white = xyz_to_rgb((0.95047, 1., 1.08883));
+    assert!((white.r - 1.).abs() < 1e-3 && (white.g - 1.).abs() < 1e-3 && (white.b - 1.).abs() < 1e-3);
+}
+
+#[test]
+fn test_blackbody() {
+    // Wien's displacement law puts the peak of a 5800K body near 500nm
+    assert!(planck(500., 5800.) > planck(420., 5800.) && planck(500., 5800.) > planck(600., 5800.));
+    assert!(planck(600., 6000.) > planck(600., 3000.));
+    let candle = blackbody(1900.);
+    assert!(candle.r > candle.g && candle.g > candle.b);
+    let daylight = blackbody(6500.);
+    assert!((daylight.luminance() - 1.).abs() < 1e-4);
+    assert!((daylight.r - daylight.g).abs() < 0.1 && (daylight.b - daylight.g).abs() < 0.1);
+    let blue_star = blackbody(20000.);
+    assert!(blue_star.b > blue_star.g && blue_star.g > blue_star.r);
+}
+
+#[test]
+fn test_blackbody_cold() {
+    for &kelvin in [-10., 0., 100., 499., f32::NAN].iter() {
+        let c = blackbody(kelvin);
+        assert!(c.r == 0. && c.g == 0. && c.b == 0.);
+    }
+    let ember = blackbody(BLACKBODY_MIN_KELVIN);
+    assert!(ember.r.is_finite() && ember.g.is_finite() && ember.b.is_finite());
+    assert!((ember.luminance() - 1.).abs() < 1e-3 && ember.r > ember.b);
+}
+
+#[test]
+fn test_spectral_round_trip() {
+    let to_rgb = SpectrumToRgb::new();
+    // many wavelengths of an upsampled colour come back to about that colour
+    let lambdas: Vec<f32> = (0..400).map(|i| sample_wavelength((i as f32 + 0.5) / 400.)).collect();
+    for c in [Color3f {r: 1., g: 1., b: 1.}, Color3f {r: 0.8, g: 0.3, b: 0.1}, Color3f {r: 0.1, g: 0.2, b: 0.7}].iter() {
+        let values: Vec<f32> = lambdas.iter().map(|&l| rgb_to_spectrum(c, l)).collect();
+        let rgb = to_rgb.to_rgb(&lambdas, &values);
+        assert!((rgb - *c).max_channel() < 0.08 && (*c - rgb).max_channel() < 0.08, "{:?} {:?}", c, rgb);
+    }
+    let lambdas = hero_wavelengths(800.);
+    assert_eq!(lambdas[0], 800.);
+    assert!((lambdas[1] - 447.5).abs() < 1e-3 && (lambdas[3] - 682.5).abs() < 1e-3);
+}
+
+#[test]
+fn test_ior() {
+    // BK7 is 1.5168 at the D line, and disperses blue more than red
+    assert!((BK7_GLASS.at(0.) - 1.5168).abs() < 1e-4);
+    assert!(BK7_GLASS.at(450.) > BK7_GLASS.at(650.));
+    assert!((FUSED_SILICA.at(587.6) - 1.4585).abs() < 1e-4);
+    let cauchy = Ior::Cauchy { a: 1.5046, b: 4200. };
+    assert!((cauchy.at(587.6) - 1.5168).abs() < 1e-3);
+    assert!(!Ior::Constant(1.33).is_dispersive());
+}
@@ -0,0 +1,2 @@
+/* Building blocks for colour programs */
+pub mod procedural;
@@ -0,0 +1,280 @@
+use std::f32;
+use vec3::Vec3;
+use color3f::Color3f;
+use raytracer::ColorProgramResult;
+use sampler;
+
+/* Seeded noise and patterns. Everything here is a pure function of position
+ * and seed, so colour programs, being plain fns, can use it without any
+ * shared state. Noises vary over roughly unit distances: scale positions to
+ * change their frequency */
+
+/* Well mixed hash of an integer lattice point */
+pub fn hash(x: i32, y: i32, z: i32, seed: u32) -> u32 {
+    sampler::hash(seed ^ (x as u32).wrapping_mul(0x8da6b343) ^ (y as u32).wrapping_mul(0xd8163841) ^ (z as u32).wrapping_mul(0xcb1ab31f))
+}
+
+fn unit_float(h: u32) -> f32 {
+    (h >> 8) as f32 / (1u32 << 24) as f32
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn floor_i32(x: f32) -> i32 {
+    x.floor() as i32
+}
+
+/* Dot product of (x, y, z) with one of the 12 cube edge directions of Perlin 2002 */
+fn gradient(h: u32, x: f32, y: f32, z: f32) -> f32 {
+    let h = h & 15;
+    let u = if h < 8 { x } else { y };
+    let v = if h < 4 { y } else if h == 12 || h == 14 { x } else { z };
+    (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+}
+
+/* Gradient noise of Perlin 2002, "Improving Noise", in about [-1..1] */
+pub fn perlin(p: &Vec3, seed: u32) -> f32 {
+    let (x0, y0, z0) = (floor_i32(p.x), floor_i32(p.y), floor_i32(p.z));
+    let (fx, fy, fz) = (p.x - x0 as f32, p.y - y0 as f32, p.z - z0 as f32);
+    let fade = |t: f32| t * t * t * (t * (t * 6. - 15.) + 10.);
+    let (u, v, w) = (fade(fx), fade(fy), fade(fz));
+    let g = |dx: i32, dy: i32, dz: i32| {
+        gradient(hash(x0 + dx, y0 + dy, z0 + dz, seed), fx - dx as f32, fy - dy as f32, fz - dz as f32)
+    };
+    lerp(lerp(lerp(g(0, 0, 0), g(1, 0, 0), u), lerp(g(0, 1, 0), g(1, 1, 0), u), v),
+         lerp(lerp(g(0, 0, 1), g(1, 0, 1), u), lerp(g(0, 1, 1), g(1, 1, 1), u), v), w)
+}
+
+/* Simplex noise, after Gustavson 2005, "Simplex noise demystified", in about
+ * [-1..1]. Cheaper than Perlin noise, and without its axis aligned artifacts */
+pub fn simplex(p: &Vec3, seed: u32) -> f32 {
+    const F3: f32 = 1. / 3.;
+    const G3: f32 = 1. / 6.;
+    // skew into the lattice of simplices, and find which of the cell's six contains p
+    let s = (p.x + p.y + p.z) * F3;
+    let (i, j, k) = (floor_i32(p.x + s), floor_i32(p.y + s), floor_i32(p.z + s));
+    let t = (i + j + k) as f32 * G3;
+    let (x0, y0, z0) = (p.x - (i as f32 - t), p.y - (j as f32 - t), p.z - (k as f32 - t));
+    let ((i1, j1, k1), (i2, j2, k2)) = if x0 >= y0 {
+        if y0 >= z0 { ((1, 0, 0), (1, 1, 0)) }
+        else if x0 >= z0 { ((1, 0, 0), (1, 0, 1)) }
+        else { ((0, 0, 1), (1, 0, 1)) }
+    } else {
+        if y0 < z0 { ((0, 0, 1), (0, 1, 1)) }
+        else if x0 < z0 { ((0, 1, 0), (0, 1, 1)) }
+        else { ((0, 1, 0), (1, 1, 0)) }
+    };
+    let corner = |di: i32, dj: i32, dk: i32, offset: f32| {
+        let (x, y, z) = (x0 - di as f32 + offset, y0 - dj as f32 + offset, z0 - dk as f32 + offset);
+        let t = 0.6 - x*x - y*y - z*z;
+        if t < 0. {
+            0.
+        } else {
+            t * t * t * t * gradient(hash(i + di, j + dj, k + dk, seed), x, y, z)
+        }
+    };
+    32. * (corner(0, 0, 0, 0.) + corner(i1, j1, k1, G3) + corner(i2, j2, k2, 2. * G3) + corner(1, 1, 1, 3. * G3))
+}
+
+/* Cellular noise of Worley 1996, "A Cellular Texture Basis Function": the
+ * distances from p to the nearest and second nearest of a scattering of
+ * feature points, one in each unit cell */
+pub fn worley(p: &Vec3, seed: u32) -> (f32, f32) {
+    let (cx, cy, cz) = (floor_i32(p.x), floor_i32(p.y), floor_i32(p.z));
+    let mut f1 = f32::INFINITY;
+    let mut f2 = f32::INFINITY;
+    for z in cz-1..cz+2 {
+        for y in cy-1..cy+2 {
+            for x in cx-1..cx+2 {
+                let h = hash(x, y, z, seed);
+                let feature = Vec3 {x: x as f32 + unit_float(h), y: y as f32 + unit_float(sampler::hash(h)),
+                                       z: z as f32 + unit_float(sampler::hash(sampler::hash(h)))};
+                let dist = (feature - *p).length();
+                if dist < f1 {
+                    f2 = f1;
+                    f1 = dist;
+                } else if dist < f2 {
+                    f2 = dist;
+                }
+            }
+        }
+    }
+    (f1, f2)
+}
+
+/* Noise that fractals are summed from */
+#[derive(Clone, Copy, Debug)]
+pub enum Basis {
+    Perlin,
+    Simplex,
+    // distance to the nearest feature point, remapped to about [-1..1]
+    Worley
+}
+
+impl Basis {
+    pub fn eval(&self, p: &Vec3, seed: u32) -> f32 {
+        match *self {
+            Basis::Perlin => perlin(p, seed),
+            Basis::Simplex => simplex(p, seed),
+            Basis::Worley => (2. * worley(p, seed).0 - 1.).min(1.)
+        }
+    }
+}
+
+/* Octaves of basis noise, each at 'lacunarity' times the frequency and 'gain'
+ * times the amplitude of the one before. Plain data, so fractals can be
+ * statics shared by colour programs */
+#[derive(Clone, Copy, Debug)]
+pub struct Fractal {
+    pub basis: Basis,
+    pub seed: u32,
+    pub octaves: u32,
+    pub lacunarity: f32,
+    pub gain: f32
+}
+
+impl Fractal {
+    pub fn new(basis: Basis, seed: u32) -> Fractal {
+        Fractal { basis: basis, seed: seed, octaves: 6, lacunarity: 2., gain: 0.5 }
+    }
+
+    fn sum<F>(&self, p: &Vec3, octave_value: F) -> f32 where F: Fn(f32) -> f32 {
+        let mut sum = 0.;
+        let mut total = 0.;
+        let mut amplitude = 1.;
+        let mut p = *p;
+        for octave in 0..self.octaves.max(1) {
+            sum += amplitude * octave_value(self.basis.eval(&p, self.seed.wrapping_add(octave)));
+            total += amplitude;
+            amplitude *= self.gain;
+            p = p.smul(self.lacunarity);
+        }
+        sum / total
+    }
+
+    /* Fractional Brownian motion, in about [-1..1] */
+    pub fn fbm(&self, p: &Vec3) -> f32 {
+        self.sum(p, |n| n)
+    }
+
+    /* fBm of the noise's magnitude, in [0..1]: billowy, like flames and marble veins */
+    pub fn turbulence(&self, p: &Vec3) -> f32 {
+        self.sum(p, |n| n.abs().min(1.))
+    }
+
+    /* Ridged multifractal of Musgrave 1994, in [0..1]: sharp crests where the
+     * noise crosses zero, with finer detail where the crests are, like
+     * mountain ranges */
+    pub fn ridged(&self, p: &Vec3) -> f32 {
+        let mut sum = 0.;
+        let mut total = 0.;
+        let mut amplitude = 1.;
+        let mut weight = 1.;
+        let mut p = *p;
+        for octave in 0..self.octaves.max(1) {
+            let ridge = 1. - self.basis.eval(&p, self.seed.wrapping_add(octave)).abs().min(1.);
+            let signal = ridge * ridge * weight;
+            weight = (2. * signal).min(1.);
+            sum += amplitude * signal;
+            total += amplitude;
+            amplitude *= self.gain;
+            p = p.smul(self.lacunarity);
+        }
+        sum / total
+    }
+
+    /* 'p' displaced by up to 'strength' along each axis by fBm. Patterns
+     * evaluated at warped points swirl and fold, as in Quilez, "Domain Warping" */
+    pub fn warp(&self, p: &Vec3, strength: f32) -> Vec3 {
+        let offset = |seed_offset: u32| Fractal { seed: self.seed.wrapping_add(seed_offset), ..*self }.fbm(p);
+        *p + Vec3 {x: offset(101), y: offset(202), z: offset(303)}.smul(strength)
+    }
+}
+
+/* 1 and 0 on alternate unit squares of a surface parameterised by (u, v) */
+pub fn checker(u: f32, v: f32) -> f32 {
+    ((floor_i32(u) + floor_i32(v)) & 1) as f32
+}
+
+/* 1 over the first 'duty' of each unit period of x, and 0 over the rest */
+pub fn stripes(x: f32, duty: f32) -> f32 {
+    if x - x.floor() < duty { 1. } else { 0. }
+}
+
+/* 1 on lines 'line_width' wide along the edges of unit squares of (u, v), and 0 inside them */
+pub fn grid(u: f32, v: f32, line_width: f32) -> f32 {
+    let half = 0.5 * line_width;
+    let on_line = |x: f32| { let f = x - x.floor(); f < half || f > 1. - half };
+    if on_line(u) || on_line(v) { 1. } else { 0. }
+}
+
+/* Colour at 't' along a ramp of (position, colour) stops in order of
+ * position, interpolated linearly and held beyond the ends */
+pub fn color_ramp(stops: &[(f32, Color3f)], t: f32) -> Color3f {
+    match stops.iter().position(|&(pos, _)| pos > t) {
+        None => stops.last().map_or(Color3f::black(), |&(_, c)| c),
+        Some(0) => stops[0].1,
+        Some(i) => {
+            let (pos0, c0) = stops[i - 1];
+            let (pos1, c1) = stops[i];
+            c0.lerp(&c1, (t - pos0) / (pos1 - pos0))
+        }
+    }
+}
+
+/* Colour program result for a surface of colour 'albedo', that doesn't glow */
+pub fn albedo(albedo: Color3f) -> ColorProgramResult {
+    ColorProgramResult { transmissive: albedo, emissive: Color3f::black() }
+}
+
+#[test]
+fn test_noise() {
+    for &basis in [Basis::Perlin, Basis::Simplex, Basis::Worley].iter() {
+        let mut sum = 0.;
+        let mut sum_sq = 0.;
+        for i in 0..4000 {
+            let p = Vec3 {x: i as f32 * 0.137, y: (i % 37) as f32 * 0.71 - 9., z: (i % 11) as f32 * 1.3};
+            let n = basis.eval(&p, 7);
+            assert!(n >= -1.1 && n <= 1.1, "{:?} {}", basis, n);
+            // the same position and seed always give the same value
+            assert_eq!(n, basis.eval(&p, 7));
+            sum += n;
+            sum_sq += n * n;
+        }
+        let mean = sum / 4000.;
+        assert!(sum_sq / 4000. - mean * mean > 0.01, "{:?} is flat", basis);
+        assert!(basis.eval(&Vec3 {x: 0.3, y: 0.6, z: 0.1}, 1) != basis.eval(&Vec3 {x: 0.3, y: 0.6, z: 0.1}, 2));
+    }
+    // gradient noise vanishes on the lattice
+    assert_eq!(perlin(&Vec3 {x: 3., y: -2., z: 5.}, 0), 0.);
+    let (f1, f2) = worley(&Vec3 {x: 0.5, y: 0.5, z: 0.5}, 3);
+    assert!(f1 <= f2 && f2 < 1.8);
+}
+
+#[test]
+fn test_fractals() {
+    let fractal = Fractal::new(Basis::Simplex, 11);
+    for i in 0..500 {
+        let p = Vec3 {x: i as f32 * 0.31, y: 0.2, z: -(i as f32) * 0.07};
+        assert!(fractal.fbm(&p).abs() <= 1.1);
+        let t = fractal.turbulence(&p);
+        assert!(t >= 0. && t <= 1.);
+        let r = fractal.ridged(&p);
+        assert!(r >= 0. && r <= 1.);
+        assert!((fractal.warp(&p, 0.5) - p).length() <= 0.5 * 1.1 * 3f32.sqrt());
+    }
+}
+
+#[test]
+fn test_patterns() {
+    assert_eq!((checker(0.5, 0.5), checker(1.5, 0.5), checker(-0.5, 0.5)), (0., 1., 1.));
+    assert_eq!((stripes(2.2, 0.25), stripes(2.3, 0.25)), (1., 0.));
+    assert_eq!((grid(3.01, 0.5, 0.1), grid(2.99, 0.5, 0.1), grid(0.5, 0.5, 0.1)), (1., 1., 0.));
+    let black = Color3f::black();
+    let white = Color3f {r: 1., g: 1., b: 1.};
+    let ramp = [(0.2, black), (0.6, white)];
+    assert_eq!((color_ramp(&ramp, 0.).r, color_ramp(&ramp, 1.).r), (0., 1.));
+    assert!((color_ramp(&ramp, 0.4).r - 0.5).abs() < 1e-6);
+}
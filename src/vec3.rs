@@ -1,5 +1,5 @@
 use std::f32;
-use std::ops::{Add, Sub, Neg};
+use std::ops::{Add, Sub, Neg, Mul, Div, AddAssign, SubAssign, Index, IndexMut};
 
 #[derive(Debug, Copy, Clone, Default)]
 pub struct Vec3 {
@@ -38,6 +38,58 @@ impl Sub for Vec3 {
     }
 }
 
+impl AddAssign for Vec3 {
+    fn add_assign(&mut self, rhs: Vec3) {
+        *self = *self + rhs;
+    }
+}
+
+impl SubAssign for Vec3 {
+    fn sub_assign(&mut self, rhs: Vec3) {
+        *self = *self - rhs;
+    }
+}
+
+impl Mul<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn mul(self, s: f32) -> Vec3 {
+        self.smul(s)
+    }
+}
+
+impl Div<f32> for Vec3 {
+    type Output = Vec3;
+
+    fn div(self, s: f32) -> Vec3 {
+        self.smul(1.0 / s)
+    }
+}
+
+impl Index<usize> for Vec3 {
+    type Output = f32;
+
+    fn index(&self, i: usize) -> &f32 {
+        match i {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 index out of range: {}", i)
+        }
+    }
+}
+
+impl IndexMut<usize> for Vec3 {
+    fn index_mut(&mut self, i: usize) -> &mut f32 {
+        match i {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("Vec3 index out of range: {}", i)
+        }
+    }
+}
+
 impl Vec3 {
     pub fn length(&self) -> f32 { f32::sqrt(self.dot(self)) }
     pub fn dot(&self, other: &Vec3) -> f32 { self.x*other.x + self.y*other.y + self.z*other.z }
@@ -52,4 +104,68 @@ impl Vec3 {
     pub fn normal(&self) -> Vec3 {
         self.smul(1.0 / self.length())
     }
+    // component-wise multiply
+    pub fn cmul(&self, other: &Vec3) -> Vec3 {
+        Vec3 { x: self.x*other.x, y: self.y*other.y, z: self.z*other.z }
+    }
+    pub fn min(&self, other: &Vec3) -> Vec3 {
+        Vec3 { x: self.x.min(other.x), y: self.y.min(other.y), z: self.z.min(other.z) }
+    }
+    pub fn max(&self, other: &Vec3) -> Vec3 {
+        Vec3 { x: self.x.max(other.x), y: self.y.max(other.y), z: self.z.max(other.z) }
+    }
+    pub fn abs(&self) -> Vec3 {
+        Vec3 { x: self.x.abs(), y: self.y.abs(), z: self.z.abs() }
+    }
+    pub fn lerp(&self, other: &Vec3, t: f32) -> Vec3 {
+        *self + (*other - *self).smul(t)
+    }
+    /* Mirror this direction about the surface normal */
+    pub fn reflect(&self, normal: &Vec3) -> Vec3 {
+        *self - normal.smul(2. * self.dot(normal))
+    }
+    /* Bend this (unit) direction through a surface with (unit) normal facing against it,
+     * where eta is the ratio of refractive indices n1/n2. None on total internal reflection */
+    pub fn refract(&self, normal: &Vec3, eta: f32) -> Option<Vec3> {
+        let c1 = -normal.dot(self);
+        let k = 1. - eta*eta * (1. - c1*c1);
+        if k < 0. {
+            None
+        } else {
+            Some((self.smul(eta) + normal.smul(eta * c1 - k.sqrt())).normal())
+        }
+    }
+}
+
+#[test]
+fn test_vec3_ops() {
+    let mut v = Vec3 {x: 1.0, y: -2.0, z: 4.0};
+    let w = Vec3 {x: 3.0, y: 1.0, z: -1.0};
+    assert_eq!(v * 2.0, Vec3 {x: 2.0, y: -4.0, z: 8.0});
+    assert_eq!(v / 2.0, Vec3 {x: 0.5, y: -1.0, z: 2.0});
+    assert_eq!(v.cmul(&w), Vec3 {x: 3.0, y: -2.0, z: -4.0});
+    assert_eq!(v.min(&w), Vec3 {x: 1.0, y: -2.0, z: -1.0});
+    assert_eq!(v.max(&w), Vec3 {x: 3.0, y: 1.0, z: 4.0});
+    assert_eq!(v.abs(), Vec3 {x: 1.0, y: 2.0, z: 4.0});
+    assert_eq!(v.lerp(&w, 0.5), Vec3 {x: 2.0, y: -0.5, z: 1.5});
+    assert_eq!((v[0], v[1], v[2]), (1.0, -2.0, 4.0));
+    v[1] = 5.0;
+    v += w;
+    assert_eq!(v, Vec3 {x: 4.0, y: 6.0, z: 3.0});
+    v -= w;
+    assert_eq!(v, Vec3 {x: 1.0, y: 5.0, z: 4.0});
+}
+
+#[test]
+fn test_vec3_reflect_refract() {
+    let n = Vec3 {x: 0.0, y: 1.0, z: 0.0};
+    let d = Vec3 {x: 1.0, y: -1.0, z: 0.0}.normal();
+    assert_eq!(d.reflect(&n), Vec3 {x: d.x, y: -d.y, z: 0.0});
+    // matched indices pass straight through
+    assert!((d.refract(&n, 1.0).unwrap() - d).length() < 1e-6);
+    // snell's law: n1 sin(theta1) = n2 sin(theta2)
+    let t = d.refract(&n, 1.0 / 1.5).unwrap();
+    assert!((t.x * 1.5 - d.x).abs() < 1e-5 && t.y < 0.);
+    // leaving a dense medium at 45 degrees is beyond the critical angle
+    assert!(d.refract(&n, 1.5).is_none());
 }
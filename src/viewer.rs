@@ -0,0 +1,121 @@
+use color3f::Color3f;
+
+/* What the preview window shows */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum View {
+    Beauty,
+    // of the first surface seen through each pixel: its normal mapped from [-1..1]
+    Normal,
+    // its colour program's transmissive colour
+    Albedo,
+    // its distance, white nearby fading to black at the furthest
+    Depth,
+    // relative standard error of each pixel's estimate, which adaptive sampling drives down
+    Variance,
+    // path samples traced per pixel
+    Samples
+}
+
+impl View {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            View::Beauty => "beauty",
+            View::Normal => "normal",
+            View::Albedo => "albedo",
+            View::Depth => "depth",
+            View::Variance => "variance",
+            View::Samples => "samples"
+        }
+    }
+
+    /* Whether the view is of the first surfaces hit, which one ray per pixel finds */
+    pub fn is_first_hit(&self) -> bool {
+        match *self {
+            View::Normal | View::Albedo | View::Depth => true,
+            _ => false
+        }
+    }
+}
+
+/* Nearest neighbour resampling of an image of 'from' size to 'to' */
+pub fn resample(image: &[Color3f], from: (u32, u32), to: (u32, u32)) -> Vec<Color3f> {
+    (0..to.0 * to.1).map(|n| {
+        let (x, y) = (n % to.0, n / to.0);
+        image[((y * from.1 / to.1) * from.0 + x * from.0 / to.0) as usize]
+    }).collect()
+}
+
+/* 3x5 pixel font for the preview's heads up display. Each row's bits are
+ * its pixels, left to right from the highest */
+pub const GLYPH_WIDTH: u32 = 3;
+pub const GLYPH_HEIGHT: u32 = 5;
+const FONT: [(char, [u8; 5]); 44] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]), ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]), ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]), ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]), ('7', [0b111, 0b001, 0b001, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]), ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]), ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]), ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]), ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]), ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]), ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]), ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]), ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]), ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]), ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]), ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]), ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]), ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]), ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]), ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]), ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]), ('(', [0b010, 0b100, 0b100, 0b100, 0b010]),
+    (')', [0b010, 0b001, 0b001, 0b001, 0b010]), ('?', [0b111, 0b001, 0b010, 0b000, 0b010])
+];
+
+fn glyph(c: char) -> [u8; 5] {
+    if c == ' ' {
+        return [0; 5];
+    }
+    let c = c.to_ascii_uppercase();
+    FONT.iter().find(|&&(g, _)| g == c).unwrap_or(&FONT[FONT.len() - 1]).1
+}
+
+/* Size in font pixels of a line of text, with a pixel between characters */
+pub fn text_size(text: &str) -> (u32, u32) {
+    let n = text.chars().count() as u32;
+    (if n > 0 { n * (GLYPH_WIDTH + 1) - 1 } else { 0 }, GLYPH_HEIGHT)
+}
+
+/* Positions of the lit pixels of a line of text, in font pixels from its top left */
+pub fn text_pixels(text: &str) -> Vec<(u32, u32)> {
+    let mut pixels = Vec::new();
+    for (i, c) in text.chars().enumerate() {
+        let rows = glyph(c);
+        for y in 0..GLYPH_HEIGHT {
+            for x in 0..GLYPH_WIDTH {
+                if rows[y as usize] & (1 << (GLYPH_WIDTH - 1 - x)) != 0 {
+                    pixels.push((i as u32 * (GLYPH_WIDTH + 1) + x, y));
+                }
+            }
+        }
+    }
+    pixels
+}
+
+#[test]
+fn test_viewer() {
+    assert_eq!(text_size("12 spp"), (23, 5));
+    assert_eq!(text_pixels("1"), vec![(1, 0), (0, 1), (1, 1), (1, 2), (1, 3), (0, 4), (1, 4), (2, 4)]);
+    // lower case is shown as upper, and unknown characters as '?'
+    assert_eq!(text_pixels("ms"), text_pixels("MS"));
+    assert_eq!(text_pixels("#"), text_pixels("?"));
+    assert!(text_pixels(" ").is_empty());
+    assert!(View::Depth.is_first_hit() && !View::Variance.is_first_hit());
+
+    let image: Vec<Color3f> = (0..4).map(|i| Color3f {r: i as f32, g: 0., b: 0.}).collect();
+    let big = resample(&image, (2, 2), (4, 4));
+    assert_eq!(big.iter().map(|c| c.r).collect::<Vec<f32>>(),
+               vec![0., 0., 1., 1., 0., 0., 1., 1., 2., 2., 3., 3., 2., 2., 3., 3.]);
+}
@@ -0,0 +1,256 @@
+use std::f32;
+use std::f32::consts::PI;
+use rand::Rng;
+use vec3::Vec3;
+use color3f::Color3f;
+use raytracer::Ray;
+use sampler::Sampler;
+use sampling::Onb;
+use textures::procedural::{Fractal,Basis};
+
+/* Participating media filling the inside of closed primitives. A medium's
+ * boundary has no surface of its own: rays cross it untouched, and inside it
+ * they are scattered at collisions found by delta tracking, with shadow rays
+ * attenuated by ratio tracking (Novák et al. 2014, "Residual Ratio Tracking
+ * for Estimating Attenuation in Participating Media") */
+#[derive(Clone, Debug)]
+pub struct Medium {
+    pub density: Density,
+    // extinction coefficient (per unit distance) where density is 1, at REFERENCE_WAVELENGTH
+    pub extinction: f32,
+    // fraction of extinction that is scattering rather than absorption
+    pub albedo: Color3f,
+    pub phase: Phase,
+    // extinction goes as (REFERENCE_WAVELENGTH / lambda)^spectral_exponent:
+    // 0 for grey media, 4 for Rayleigh scattering
+    pub spectral_exponent: f32
+}
+
+pub const REFERENCE_WAVELENGTH: f32 = 550.;
+// wavelengths standing in for the red, green and blue primaries
+const RGB_WAVELENGTHS: [f32; 3] = [610., 550., 465.];
+
+#[derive(Clone, Debug)]
+pub enum Density {
+    Constant(f32),
+    // exp(-height / scale_height), with height above the sphere 'radius' about 'center'
+    Exponential { center: Vec3, radius: f32, scale_height: f32 },
+    // fBm of Perlin noise mapped to [0..1], 'frequency' cells per unit distance
+    Noise { frequency: f32, octaves: u32, seed: u32 }
+}
+
+impl Density {
+    pub fn at(&self, pos: &Vec3) -> f32 {
+        match *self {
+            Density::Constant(d) => d,
+            Density::Exponential { center, radius, scale_height } => {
+                let height = ((*pos - center).length() - radius).max(0.);
+                (-height / scale_height).exp()
+            }
+            Density::Noise { frequency, octaves, seed } => {
+                let fractal = Fractal { octaves: octaves, ..Fractal::new(Basis::Perlin, seed) };
+                (0.5 + 0.5 * fractal.fbm(&pos.smul(frequency))).max(0.).min(1.)
+            }
+        }
+    }
+
+    /* Upper bound on density anywhere */
+    pub fn max(&self) -> f32 {
+        match *self {
+            Density::Constant(d) => d,
+            Density::Exponential { .. } | Density::Noise { .. } => 1.
+        }
+    }
+}
+
+impl Medium {
+    /* Extinction at 'lambda' nm. Rays not rendered spectrally have wavelength
+     * 0, and are tracked at the blue primary, where chromatic media are densest */
+    pub fn extinction_at(&self, lambda: f32) -> f32 {
+        let lambda = if lambda > 0. { lambda } else { RGB_WAVELENGTHS[2] };
+        self.extinction * (REFERENCE_WAVELENGTH / lambda).powf(self.spectral_exponent)
+    }
+
+    pub fn is_chromatic(&self) -> bool {
+        self.spectral_exponent != 0.
+    }
+
+    /* Fraction of light scattered at a collision. RGB rays collide as often as
+     * blue light would, so red and green scatter in proportion to how much
+     * thinner the medium is for them. Transmittance stays grey in RGB: only
+     * spectral rendering reddens light that has come a long way through */
+    pub fn scattering_color(&self, lambda: f32) -> Color3f {
+        if lambda > 0. || !self.is_chromatic() {
+            return self.albedo;
+        }
+        let relative = |l: f32| (RGB_WAVELENGTHS[2] / l).powf(self.spectral_exponent);
+        Color3f {
+            r: self.albedo.r * relative(RGB_WAVELENGTHS[0]),
+            g: self.albedo.g * relative(RGB_WAVELENGTHS[1]),
+            b: self.albedo.b
+        }
+    }
+
+    /* Delta tracking: distance along 'ray' to its first real collision, if one comes before 'max_dist' */
+    pub fn sample_collision(&self, ray: &Ray, max_dist: f32, rng: &mut Sampler) -> Option<f32> {
+        let extinction = self.extinction_at(ray.wavelength);
+        let majorant = self.density.max() * extinction;
+        if majorant <= 0. {
+            return None;
+        }
+        let mut t = 0.;
+        loop {
+            t -= (1. - rng.gen::<f32>()).ln() / majorant;
+            if t >= max_dist {
+                return None;
+            }
+            let pos = ray.origin + ray.dir.smul(t);
+            if rng.gen::<f32>() * majorant < self.density.at(&pos) * extinction {
+                return Some(t);
+            }
+        }
+    }
+
+    /* Ratio tracking: unbiased estimate of the fraction of light getting 'max_dist' along 'ray' */
+    pub fn transmittance(&self, ray: &Ray, max_dist: f32, rng: &mut Sampler) -> f32 {
+        let extinction = self.extinction_at(ray.wavelength);
+        let majorant = self.density.max() * extinction;
+        if majorant <= 0. {
+            return 1.;
+        }
+        let mut t = 0.;
+        let mut transmittance = 1.;
+        loop {
+            t -= (1. - rng.gen::<f32>()).ln() / majorant;
+            if t >= max_dist {
+                return transmittance;
+            }
+            let pos = ray.origin + ray.dir.smul(t);
+            transmittance *= 1. - self.density.at(&pos) * extinction / majorant;
+        }
+    }
+}
+
+/* Angular distribution of scattered light, as a function of the cosine of the
+ * angle between the incoming and outgoing directions of travel */
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+    Isotropic,
+    // asymmetry g in (-1..1): positive scatters forwards
+    HenyeyGreenstein(f32),
+    // small particles, such as air molecules
+    Rayleigh,
+    // larger particles such as haze, by the Cornette-Shanks fit with asymmetry g
+    Mie(f32)
+}
+
+impl Phase {
+    pub fn eval(&self, cos_theta: f32) -> f32 {
+        match *self {
+            Phase::Isotropic => 0.25 / PI,
+            Phase::HenyeyGreenstein(g) => henyey_greenstein(g, cos_theta),
+            Phase::Rayleigh => 3. / (16. * PI) * (1. + cos_theta * cos_theta),
+            Phase::Mie(g) => {
+                1.5 * (1. + cos_theta * cos_theta) / (2. + g * g) * henyey_greenstein(g, cos_theta)
+            }
+        }
+    }
+
+    /* New direction of travel for light travelling along 'dir', distributed exactly as eval() */
+    pub fn sample(&self, dir: &Vec3, rng: &mut Sampler) -> Vec3 {
+        let u = rng.next_2d();
+        let cos_theta = match *self {
+            Phase::Isotropic => 1. - 2. * u.0,
+            Phase::HenyeyGreenstein(g) => sample_henyey_greenstein(g, u.0),
+            Phase::Rayleigh => {
+                // invert the CDF, a cubic in cos_theta, by Cardano's formula
+                let a = 4. * u.0 - 2.;
+                let s = (a * a + 1.).sqrt();
+                (a + s).cbrt() + (a - s).cbrt()
+            }
+            Phase::Mie(g) => {
+                // Henyey-Greenstein samples, kept in proportion to (1 + cos^2) / 2. every
+                // attempt draws its sample and the test for keeping it from the sampler
+                loop {
+                    let v = rng.next_2d();
+                    let cos_theta = sample_henyey_greenstein(g, v.0);
+                    if v.1 * 2. <= 1. + cos_theta * cos_theta {
+                        break cos_theta;
+                    }
+                }
+            }
+        }.max(-1.).min(1.);
+        let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+        let phi = 2. * PI * u.1;
+        Onb::from_normal(&dir.normal()).to_world(&Vec3 {x: sin_theta * phi.cos(), y: sin_theta * phi.sin(), z: cos_theta})
+    }
+}
+
+fn henyey_greenstein(g: f32, cos_theta: f32) -> f32 {
+    let denom = 1. + g * g - 2. * g * cos_theta;
+    (1. - g * g) / (4. * PI * denom * denom.sqrt())
+}
+
+fn sample_henyey_greenstein(g: f32, u: f32) -> f32 {
+    if g.abs() < 1e-3 {
+        1. - 2. * u
+    } else {
+        let s = (1. - g * g) / (1. - g + 2. * g * u);
+        (1. + g * g - s * s) / (2. * g)
+    }
+}
+
+#[test]
+fn test_phase_functions() {
+    // each phase function integrates to one over the sphere
+    for phase in [Phase::Isotropic, Phase::HenyeyGreenstein(0.7), Phase::HenyeyGreenstein(-0.3),
+                  Phase::Rayleigh, Phase::Mie(0.76)].iter() {
+        let n = 20000;
+        let integral: f32 = (0..n).map(|i| phase.eval(1. - 2. * (i as f32 + 0.5) / n as f32)).sum::<f32>() * 4. * PI / n as f32;
+        assert!((integral - 1.).abs() < 1e-2, "{:?} {}", phase, integral);
+    }
+    assert!(Phase::HenyeyGreenstein(0.7).eval(1.) > Phase::HenyeyGreenstein(0.7).eval(-1.));
+    assert_eq!(Phase::Rayleigh.eval(1.), Phase::Rayleigh.eval(-1.));
+}
+
+#[test]
+fn test_phase_sampling() {
+    let mut rng = Sampler::new(::sampler::SamplerKind::Random, ::rand::thread_rng());
+    let dir = Vec3 {x: 0., y: 0., z: -1.};
+    for &(phase, mean_cos) in [(Phase::Isotropic, 0.), (Phase::HenyeyGreenstein(0.6), 0.6), (Phase::Rayleigh, 0.)].iter() {
+        let n = 20000;
+        let sum: f32 = (0..n).map(|_| {
+            let d = phase.sample(&dir, &mut rng);
+            assert!((d.length() - 1.).abs() < 1e-3);
+            d.dot(&dir)
+        }).sum();
+        assert!((sum / n as f32 - mean_cos).abs() < 0.03, "{:?} {}", phase, sum / n as f32);
+    }
+}
+
+#[test]
+fn test_tracking() {
+    let mut rng = Sampler::new(::sampler::SamplerKind::Random, ::rand::thread_rng());
+    let ray = Ray {origin: Vec3 {x: 0., y: 0., z: 0.}, dir: Vec3 {x: 1., y: 0., z: 0.}, ..Ray::default()};
+    // a medium at half density with extinction 2 transmits exp(-1) over unit distance
+    let medium = Medium { density: Density::Constant(0.5), extinction: 2., albedo: Color3f::default(), phase: Phase::Isotropic,
+                          spectral_exponent: 0. };
+    let n = 20000;
+    let escaped = (0..n).filter(|_| medium.sample_collision(&ray, 1., &mut rng).is_none()).count();
+    assert!((escaped as f32 / n as f32 - (-1f32).exp()).abs() < 0.02);
+    let noise = Medium { density: Density::Noise { frequency: 3., octaves: 4, seed: 1 }, ..medium.clone() };
+    let mean_t: f32 = (0..n).map(|_| noise.transmittance(&ray, 1., &mut rng)).sum::<f32>() / n as f32;
+    let escaped = (0..n).filter(|_| noise.sample_collision(&ray, 1., &mut rng).is_none()).count();
+    assert!((mean_t - escaped as f32 / n as f32).abs() < 0.02);
+    // Rayleigh scattering thins out towards the red
+    let air = Medium { phase: Phase::Rayleigh, spectral_exponent: 4., albedo: Color3f {r: 1., g: 1., b: 1.}, ..medium.clone() };
+    assert_eq!(air.extinction_at(550.), 2.);
+    assert!(air.extinction_at(450.) > 2. * air.extinction_at(650.));
+    let rgb = air.scattering_color(0.);
+    assert!(rgb.b == 1. && rgb.g < rgb.b && rgb.r < rgb.g);
+    assert_eq!(air.scattering_color(700.).r, 1.);
+    for i in 0..100 {
+        let d = noise.density.at(&Vec3 {x: i as f32 * 0.37, y: -1.3, z: 0.2});
+        assert!(d >= 0. && d <= 1.);
+    }
+}